@@ -0,0 +1,277 @@
+//! Bot activity.
+
+use std::env;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Context as _, Error, Result};
+use poise::serenity_prelude::{Activity, Context, OnlineStatus, RwLock};
+
+use crate::data;
+use crate::db::Settings;
+use crate::setup;
+
+/// Default interval between activity changes.
+const INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// Variables that can be used in activity names.
+const VARS: [&str; 4] = ["guilds", "memes_served", "subs", "bot_name"];
+
+/// Type of activity.
+#[derive(Debug, Clone)]
+enum Kind {
+    Competing,
+    Listening,
+    Playing,
+    /// Streaming at a URL.
+    Streaming(String),
+    Watching,
+}
+
+/// An activity with a name that may contain template variables, e.g. `watching {guilds} servers`.
+#[derive(Debug, Clone)]
+pub struct Template {
+    kind: Kind,
+    name: String,
+}
+
+impl FromStr for Template {
+    type Err = Error;
+
+    /// Parse an activity from its type (competing | listening | playing | streaming | watching) and
+    /// name. Streaming activities take the stream URL before the name.
+    fn from_str(s: &str) -> Result<Self> {
+        let (kind, name) = s
+            .trim()
+            .split_once(' ')
+            .ok_or_else(|| anyhow!("missing activity name: {s}"))?;
+        let kind = match kind.to_lowercase().as_str() {
+            "competing" => Kind::Competing,
+            "listening" => Kind::Listening,
+            "playing" => Kind::Playing,
+            "streaming" => {
+                let (url, name) = name
+                    .split_once(' ')
+                    .ok_or_else(|| anyhow!("missing stream URL or name: {s}"))?;
+                return Self::streaming(name.to_string(), url.to_string());
+            }
+            "watching" => Kind::Watching,
+            _ => bail!("invalid activity type: {kind}"),
+        };
+
+        Self::new(kind, name.to_string())
+    }
+}
+
+impl Template {
+    fn new(kind: Kind, name: String) -> Result<Self> {
+        let mut rest = name.as_str();
+        while let Some((_, var)) = rest.split_once('{') {
+            let (var, after) = var
+                .split_once('}')
+                .ok_or_else(|| anyhow!("unclosed variable in activity name: {name}"))?;
+            if !VARS.contains(&var) {
+                bail!(
+                    "unknown variable `{{{var}}}` in activity name, expected one of: {}",
+                    VARS.join(", ")
+                );
+            }
+            rest = after;
+        }
+
+        Ok(Self { kind, name })
+    }
+
+    fn streaming(name: String, url: String) -> Result<Self> {
+        // Discord only shows streaming activities with an http(s) URL
+        if !url.starts_with("https://") && !url.starts_with("http://") {
+            bail!("invalid stream URL for streaming activity: {url}");
+        }
+
+        Self::new(Kind::Streaming(url), name)
+    }
+
+    /// Create the activity, filling in template variables.
+    fn render(&self, ctx: &Context, bot_name: &str, memes_served: u64) -> Activity {
+        let mut name = String::with_capacity(self.name.len());
+        let mut rest = self.name.as_str();
+        // Variables were validated when the template was parsed
+        while let Some((before, after)) = rest.split_once('{') {
+            let (var, after) = after.split_once('}').unwrap_or((after, ""));
+            name.push_str(before);
+            match var {
+                "guilds" => name.push_str(&ctx.cache.guild_count().to_string()),
+                "memes_served" => name.push_str(&memes_served.to_string()),
+                "subs" => name.push_str(&data::all_subs().len().to_string()),
+                _ => name.push_str(bot_name),
+            }
+            rest = after;
+        }
+        name.push_str(rest);
+
+        match self.kind {
+            Kind::Competing => Activity::competing(name),
+            Kind::Listening => Activity::listening(name),
+            Kind::Playing => Activity::playing(name),
+            Kind::Streaming(ref url) => Activity::streaming(name, url),
+            Kind::Watching => Activity::watching(name),
+        }
+    }
+}
+
+/// Get the activities to rotate through, from `MEMER_ACTIVITIES` (separated by `;`) or a single
+/// `MEMER_ACTIVITY_TYPE` and `MEMER_ACTIVITY_NAME`.
+pub fn templates() -> Result<Vec<Template>> {
+    if let Ok(activities) = env::var("MEMER_ACTIVITIES") {
+        return activities
+            .split(';')
+            .filter(|activity| !activity.trim().is_empty())
+            .map(Template::from_str)
+            .collect::<Result<_>>()
+            .context("invalid MEMER_ACTIVITIES environment variable");
+    }
+
+    match (
+        env::var("MEMER_ACTIVITY_TYPE"),
+        env::var("MEMER_ACTIVITY_NAME"),
+    ) {
+        (Ok(kind), Ok(name)) if kind.trim().eq_ignore_ascii_case("streaming") => {
+            let url = env::var("MEMER_ACTIVITY_STREAMING").context(
+                "missing MEMER_ACTIVITY_STREAMING environment variable for streaming activity",
+            )?;
+            Ok(vec![Template::streaming(name, url)?])
+        }
+        (Ok(kind), Ok(name)) => Ok(vec![format!("{kind} {name}").parse()?]),
+        _ => Ok(Vec::new()),
+    }
+}
+
+/// Get the interval between activity changes.
+pub fn interval() -> Result<Duration> {
+    Ok(setup::env_duration("MEMER_ACTIVITY_INTERVAL")?.unwrap_or(INTERVAL))
+}
+
+/// The bot's presence: a rotating list of activities, or maintenance mode.
+#[derive(Debug)]
+pub struct Presence {
+    templates: RwLock<Vec<Template>>,
+    /// Index of the current template.
+    current: AtomicUsize,
+    bot_name: String,
+    memes_served: Arc<AtomicU64>,
+    settings: Arc<RwLock<Settings>>,
+}
+
+impl Presence {
+    pub fn new(
+        templates: Vec<Template>,
+        bot_name: String,
+        memes_served: Arc<AtomicU64>,
+        settings: Arc<RwLock<Settings>>,
+    ) -> Self {
+        Self {
+            templates: RwLock::new(templates),
+            current: AtomicUsize::new(0),
+            bot_name,
+            memes_served,
+            settings,
+        }
+    }
+
+    /// Show the current activity, or maintenance mode if it's enabled.
+    #[tracing::instrument(skip_all)]
+    pub async fn update(&self, ctx: &Context) {
+        if self.settings.read().await.maintenance {
+            ctx.set_presence(
+                Some(Activity::playing("under maintenance")),
+                OnlineStatus::DoNotDisturb,
+            )
+            .await;
+            return;
+        }
+
+        let activity = {
+            let templates = self.templates.read().await;
+            templates
+                .get(self.current.load(Ordering::Relaxed) % templates.len().max(1))
+                .map(|template| {
+                    template.render(
+                        ctx,
+                        &self.bot_name,
+                        self.memes_served.load(Ordering::Relaxed),
+                    )
+                })
+        };
+        ctx.set_presence(activity, OnlineStatus::Online).await;
+    }
+
+    /// Replace the activities to rotate through, and show the first one.
+    pub async fn set_templates(&self, ctx: &Context, templates: Vec<Template>) {
+        *self.templates.write().await = templates;
+        self.current.store(0, Ordering::Relaxed);
+        self.update(ctx).await;
+    }
+
+    /// Show the next activity every `interval`. Template variables are refreshed even if there is
+    /// only one activity.
+    pub async fn rotate(self: Arc<Self>, ctx: Context, interval: Duration) {
+        let mut ticker = tokio::time::interval(interval);
+
+        loop {
+            ticker.tick().await;
+            self.update(&ctx).await;
+            self.current.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_kinds_in_any_case() {
+        let kind = |s: &str| s.parse::<Template>().unwrap().kind;
+
+        assert!(matches!(
+            kind("competing in {subs} subreddits"),
+            Kind::Competing
+        ));
+        assert!(matches!(kind("Listening to {bot_name}"), Kind::Listening));
+        assert!(matches!(kind("PLAYING with memes"), Kind::Playing));
+        assert!(matches!(kind("watching {guilds} servers"), Kind::Watching));
+    }
+
+    #[test]
+    fn keeps_name_after_kind() {
+        let template = "watching {guilds} servers".parse::<Template>().unwrap();
+        assert_eq!(template.name, "{guilds} servers");
+    }
+
+    #[test]
+    fn parses_streaming_url_before_name() {
+        let template = "Streaming https://twitch.tv/memer {memes_served} memes"
+            .parse::<Template>()
+            .unwrap();
+        assert!(
+            matches!(template.kind, Kind::Streaming(ref url) if url == "https://twitch.tv/memer")
+        );
+        assert_eq!(template.name, "{memes_served} memes");
+    }
+
+    #[test]
+    fn rejects_invalid_templates() {
+        for s in [
+            "watching",
+            "sleeping on the job",
+            "streaming memes",
+            "streaming twitch.tv/memer memes",
+            "watching {servers}",
+            "watching {guilds servers",
+        ] {
+            assert!(s.parse::<Template>().is_err(), "{s}");
+        }
+    }
+}
@@ -0,0 +1,179 @@
+//! Posting to channels on a schedule.
+
+use std::str::FromStr;
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Error, Result};
+use chrono::{DateTime, Utc};
+use poise::futures_util::future;
+use poise::serenity_prelude::Context;
+use tracing::{info, warn};
+
+use crate::db::{self, Autopost};
+use crate::{posts, Data};
+
+/// Interval between checks for scheduled posts that are due.
+const TICK: Duration = Duration::from_secs(15);
+/// Shortest allowed interval between scheduled posts.
+const MIN_INTERVAL: Duration = Duration::from_secs(60);
+
+/// When to post: `every <duration>`, e.g. `every 1h 30m`, or a cron expression with seconds, e.g.
+/// `0 0 * * * *` for every hour.
+#[derive(Debug)]
+pub enum Schedule {
+    Every(Duration),
+    Cron(Box<cron::Schedule>),
+}
+
+impl FromStr for Schedule {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let s = s.trim();
+
+        if let Some(duration) = s.strip_prefix("every ") {
+            let duration = humantime::parse_duration(duration.trim())
+                .map_err(|e| anyhow!("Invalid duration `{duration}`: {e}"))?;
+            if duration < MIN_INTERVAL {
+                bail!(
+                    "Posts can be scheduled at most every {}.",
+                    humantime::format_duration(MIN_INTERVAL)
+                );
+            }
+
+            return Ok(Self::Every(duration));
+        }
+
+        let schedule = cron::Schedule::from_str(s).map_err(|e| {
+            anyhow!("Invalid schedule `{s}`, use `every <duration>` or a cron expression: {e}")
+        })?;
+        // Check the interval between the next two times as a cheap lower bound
+        let mut upcoming = schedule.upcoming(Utc);
+        if let (Some(first), Some(second)) = (upcoming.next(), upcoming.next()) {
+            if (second - first).to_std().unwrap_or_default() < MIN_INTERVAL {
+                bail!(
+                    "Posts can be scheduled at most every {}.",
+                    humantime::format_duration(MIN_INTERVAL)
+                );
+            }
+        }
+
+        Ok(Self::Cron(Box::new(schedule)))
+    }
+}
+
+impl Schedule {
+    /// The next time to post after `after`.
+    pub fn next(&self, after: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        match self {
+            Self::Every(duration) => chrono::Duration::from_std(*duration)
+                .ok()
+                .map(|duration| after + duration),
+            Self::Cron(schedule) => schedule.after(&after).next(),
+        }
+    }
+}
+
+/// Send scheduled posts when they're due, until shutdown.
+pub async fn run(ctx: Context, data: &Data) {
+    let mut ticker = tokio::time::interval(TICK);
+
+    while !data.shutdown.is_stopping() {
+        ticker.tick().await;
+
+        let now = Utc::now();
+        let due = data
+            .autoposts
+            .iter()
+            .filter(|autopost| autopost.next <= now)
+            .map(|autopost| autopost.value().clone())
+            .collect::<Vec<_>>();
+
+        future::join_all(due.into_iter().map(|autopost| post(&ctx, data, autopost))).await;
+    }
+}
+
+/// Send a scheduled post and schedule the next one.
+#[tracing::instrument(skip_all, fields(channel = %autopost.channel_id, group = %autopost.group))]
+async fn post(ctx: &Context, data: &Data, mut autopost: Autopost) {
+    // Posts missed while maintenance mode is enabled are skipped
+    if !data.settings.read().await.maintenance {
+        match posts::send(ctx, data, autopost.channel_id, &autopost.group).await {
+            Ok(_) => info!("sent scheduled post"),
+            Err(e) => warn!("failed to send scheduled post: {e:#}"),
+        }
+    }
+
+    // Posts missed while the bot was offline are only sent once
+    let next = autopost
+        .schedule
+        .parse::<Schedule>()
+        .ok()
+        .and_then(|schedule| schedule.next(Utc::now()));
+    let Some(next) = next else {
+        warn!("schedule has no upcoming times, cancelling it");
+        data.autoposts
+            .remove(&(autopost.channel_id, autopost.group.clone()));
+        if let Err(e) = db::delete_autopost(&data.db, autopost.channel_id, &autopost.group).await {
+            warn!("failed to delete schedule: {e:#}");
+        }
+        return;
+    };
+
+    autopost.next = next;
+    if let Err(e) = db::save_autopost(&data.db, &autopost).await {
+        warn!("failed to save schedule: {e:#}");
+    }
+    // The schedule may have been cancelled while posting
+    if let Some(mut entry) = data
+        .autoposts
+        .get_mut(&(autopost.channel_id, autopost.group.clone()))
+    {
+        *entry = autopost;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+
+    #[test]
+    fn parses_intervals() {
+        let schedule = "every 1h 30m".parse::<Schedule>().unwrap();
+        assert!(matches!(schedule, Schedule::Every(d) if d == Duration::from_secs(90 * 60)));
+
+        let after = Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap();
+        assert_eq!(
+            schedule.next(after),
+            Some(Utc.with_ymd_and_hms(2024, 1, 1, 1, 30, 0).unwrap())
+        );
+    }
+
+    #[test]
+    fn parses_cron_expressions() {
+        let schedule = "0 0 * * * *".parse::<Schedule>().unwrap();
+        assert!(matches!(schedule, Schedule::Cron(_)));
+
+        let after = Utc.with_ymd_and_hms(2024, 1, 1, 0, 30, 0).unwrap();
+        assert_eq!(
+            schedule.next(after),
+            Some(Utc.with_ymd_and_hms(2024, 1, 1, 1, 0, 0).unwrap())
+        );
+    }
+
+    #[test]
+    fn rejects_short_intervals() {
+        assert!("every 30s".parse::<Schedule>().is_err());
+        assert!("* * * * * *".parse::<Schedule>().is_err());
+        assert!("every 1m".parse::<Schedule>().is_ok());
+    }
+
+    #[test]
+    fn rejects_invalid_schedules() {
+        for s in ["", "every", "every often", "hourly", "0 0 * *"] {
+            assert!(s.parse::<Schedule>().is_err(), "{s}");
+        }
+    }
+}
@@ -1,3 +1,331 @@
 //! Bot commands.
 
+use std::collections::HashMap;
+use std::time::Duration;
+
+use anyhow::{bail, Error, Result};
+use once_cell::sync::OnceCell;
+use poise::futures_util::{stream, Stream};
+use poise::serenity_prelude::{
+    ButtonStyle, CollectComponentInteraction, CreateComponents, CreateEmbed,
+    InteractionResponseType,
+};
+use poise::{ApplicationContext, BoxFuture, Command, FrameworkError, PrefixContext};
+use rand::Rng;
+use tracing::{info_span, Instrument, Span};
+
+use crate::data::{self, QuickPost};
+use crate::{posts, Context, Data};
+
 pub mod admin;
+pub mod autopost;
+pub mod browse;
+pub mod digest;
+pub mod filters;
+pub mod media;
+pub mod search;
+
+/// How long a paginator waits for a button press before its buttons are disabled.
+const PAGINATE_TIMEOUT: Duration = Duration::from_secs(120);
+/// Maximum length of a message.
+const MESSAGE_LEN: usize = 2000;
+
+type ActionResult<'a> = Result<(), FrameworkError<'a, Data, Error>>;
+type PrefixAction = for<'a> fn(PrefixContext<'a, Data, Error>) -> BoxFuture<'a, ActionResult<'a>>;
+type SlashAction =
+    for<'a> fn(ApplicationContext<'a, Data, Error>) -> BoxFuture<'a, ActionResult<'a>>;
+/// A command's prefix and slash actions.
+type Actions = (Option<PrefixAction>, Option<SlashAction>);
+
+/// The actions generated for each command, by qualified name. Commands run these through
+/// [`traced_prefix`] and [`traced_slash`].
+static ACTIONS: OnceCell<HashMap<String, Actions>> = OnceCell::new();
+
+/// Run commands and their subcommands in a span with the guild, channel, user and command name, so
+/// logs and traces from handling a command can be queried by any of them.
+pub fn traced(mut commands: Vec<Command<Data, Error>>) -> Vec<Command<Data, Error>> {
+    fn wrap(
+        command: &mut Command<Data, Error>,
+        parent: Option<&str>,
+        actions: &mut HashMap<String, Actions>,
+    ) {
+        // The framework sets qualified names the same way when it starts
+        command.qualified_name = match parent {
+            Some(parent) => format!("{parent} {}", command.name),
+            None => command.name.to_string(),
+        };
+        actions.insert(
+            command.qualified_name.clone(),
+            (command.prefix_action, command.slash_action),
+        );
+        command.prefix_action = command.prefix_action.map(|_| traced_prefix as PrefixAction);
+        command.slash_action = command.slash_action.map(|_| traced_slash as SlashAction);
+
+        let name = command.qualified_name.clone();
+        for subcommand in &mut command.subcommands {
+            wrap(subcommand, Some(&name), actions);
+        }
+    }
+
+    let mut actions = HashMap::new();
+    for command in &mut commands {
+        wrap(command, None, &mut actions);
+    }
+    // Commands are only built once
+    let _ = ACTIONS.set(actions);
+
+    commands
+}
+
+/// The span a command runs in.
+fn span(ctx: Context<'_>) -> Span {
+    info_span!(
+        "command",
+        command = %ctx.command().qualified_name,
+        guild = ?ctx.guild_id(),
+        channel = %ctx.channel_id(),
+        user = %ctx.author().id,
+    )
+}
+
+fn traced_prefix(ctx: PrefixContext<'_, Data, Error>) -> BoxFuture<'_, ActionResult<'_>> {
+    // Unwrap: commands are only given this action by `traced`, which records the one it replaced
+    let action = ACTIONS
+        .get()
+        .and_then(|actions| actions.get(&ctx.command.qualified_name))
+        .and_then(|(prefix, _)| *prefix)
+        .unwrap();
+
+    Box::pin(action(ctx).instrument(span(ctx.into())))
+}
+
+fn traced_slash(ctx: ApplicationContext<'_, Data, Error>) -> BoxFuture<'_, ActionResult<'_>> {
+    // Unwrap: commands are only given this action by `traced`, which records the one it replaced
+    let action = ACTIONS
+        .get()
+        .and_then(|actions| actions.get(&ctx.command.qualified_name))
+        .and_then(|(_, slash)| *slash)
+        .unwrap();
+
+    Box::pin(action(ctx).instrument(span(ctx.into())))
+}
+
+/// Suggest subreddit groups that start with the partial input.
+pub async fn autocomplete_group(_ctx: Context<'_>, partial: String) -> impl Stream<Item = String> {
+    let mut groups = data::SUBS
+        .iter()
+        .map(|group| group.key().clone())
+        .filter(|group| group.starts_with(&partial.to_lowercase()))
+        .collect::<Vec<_>>();
+    groups.sort_unstable();
+
+    stream::iter(groups)
+}
+
+/// Suggest subreddit groups and subreddits that start with the partial input.
+pub async fn autocomplete_source(_ctx: Context<'_>, partial: String) -> impl Stream<Item = String> {
+    let partial = partial.trim_start_matches("r/").to_lowercase();
+    let mut groups = data::SUBS
+        .iter()
+        .map(|group| group.key().clone())
+        .filter(|group| group.starts_with(&partial))
+        .collect::<Vec<_>>();
+    groups.sort_unstable();
+    groups.extend(
+        data::all_subs()
+            .into_iter()
+            .filter(|sub| sub.to_lowercase().starts_with(&partial)),
+    );
+    // Discord shows at most 25 choices
+    groups.truncate(25);
+
+    stream::iter(groups)
+}
+
+/// Join lines into a message, leaving out lines that don't fit.
+pub fn join_lines(lines: &[String]) -> String {
+    fit_lines(lines, MESSAGE_LEN)
+}
+
+/// Put lines in a code block, leaving out lines that don't fit in a message.
+pub fn code_block(lines: &[String]) -> String {
+    // Minus the code block fences
+    format!("```\n{}```", fit_lines(lines, MESSAGE_LEN - 8))
+}
+
+/// Join lines, ending with a note of how many were left out if they don't fit in `max_len`.
+fn fit_lines(lines: &[String], max_len: usize) -> String {
+    // Minus the omitted lines note
+    let max_len = max_len - 32;
+
+    let mut joined = String::new();
+    let mut len = 0;
+    for (i, line) in lines.iter().enumerate() {
+        len += line.len() + 1;
+        if len > max_len {
+            joined.push_str(&format!("... {} more\n", lines.len() - i));
+            break;
+        }
+        joined.push_str(line);
+        joined.push('\n');
+    }
+
+    joined
+}
+
+/// Show posts one at a time with previous, next and random buttons that only the command's author
+/// can use. Only the first post is recorded as sent to the channel, flipping through pages doesn't
+/// blacklist them. Posts that fail validation are skipped.
+pub async fn paginate(ctx: Context<'_>, mut posts: Vec<QuickPost>) -> Result<()> {
+    let data = ctx.data();
+    let channel = ctx.channel_id();
+    // Button IDs are unique to this invocation
+    let prefix = format!("paginate-{}-", ctx.id());
+    let (prev, next, random) = (
+        format!("{prefix}prev"),
+        format!("{prefix}next"),
+        format!("{prefix}random"),
+    );
+    let buttons = |c: &mut CreateComponents, disabled: bool| {
+        c.create_action_row(|row| {
+            for (id, label) in [(&prev, "Previous"), (&random, "Random"), (&next, "Next")] {
+                row.create_button(|b| {
+                    b.custom_id(id)
+                        .label(label)
+                        .style(ButtonStyle::Secondary)
+                        .disabled(disabled)
+                });
+            }
+            row
+        });
+    };
+    let render = |posts: &[QuickPost], page: usize| {
+        let mut e = CreateEmbed::default();
+        posts::embed(data, &posts[page], &mut e);
+        e
+    };
+
+    // Validation can take longer than Discord waits for a response
+    ctx.defer().await?;
+    let Some(mut page) = valid_page(data, &mut posts, 0, Direction::Next).await else {
+        bail!("There are no posts left for this channel, try again later!");
+    };
+
+    posts::served(data, channel, &posts[page]);
+    let reply = ctx
+        .send(|m| {
+            m.content(format!("{}/{}", page + 1, posts.len()))
+                .embed(|e| posts::embed(data, &posts[page], e))
+                .components(|c| {
+                    buttons(c, false);
+                    posts::components(&posts[page], c)
+                })
+        })
+        .await?;
+
+    while let Some(interaction) = CollectComponentInteraction::new(ctx.discord())
+        .channel_id(channel)
+        .filter({
+            let prefix = prefix.clone();
+            move |interaction| interaction.data.custom_id.starts_with(&prefix)
+        })
+        .timeout(PAGINATE_TIMEOUT)
+        .await
+    {
+        if interaction.user.id != ctx.author().id {
+            interaction
+                .create_interaction_response(ctx.discord(), |r| {
+                    r.kind(InteractionResponseType::ChannelMessageWithSource)
+                        .interaction_response_data(|d| {
+                            d.content(format!("Only {} can use these buttons.", ctx.author()))
+                                .ephemeral(true)
+                        })
+                })
+                .await?;
+            continue;
+        }
+        interaction.defer(ctx.discord()).await?;
+
+        let id = &interaction.data.custom_id;
+        let (from, direction) = if *id == prev {
+            ((page + posts.len() - 1) % posts.len(), Direction::Prev)
+        } else if *id == next {
+            ((page + 1) % posts.len(), Direction::Next)
+        } else {
+            (
+                rand::thread_rng().gen_range(0..posts.len()),
+                Direction::Random,
+            )
+        };
+
+        let Some(valid) = valid_page(data, &mut posts, from, direction).await else {
+            interaction
+                .edit_original_interaction_response(ctx.discord(), |r| {
+                    r.content("There are no posts left for this channel, try again later!")
+                        .set_embeds(Vec::new())
+                        .components(|c| c)
+                })
+                .await?;
+            return Ok(());
+        };
+        page = valid;
+
+        interaction
+            .edit_original_interaction_response(ctx.discord(), |r| {
+                r.content(format!("{}/{}", page + 1, posts.len()))
+                    .set_embed(render(&posts, page))
+                    .components(|c| {
+                        buttons(c, false);
+                        posts::components(&posts[page], c)
+                    })
+            })
+            .await?;
+    }
+
+    reply
+        .edit(ctx, |m| {
+            m.components(|c| {
+                buttons(c, true);
+                posts::components(&posts[page], c)
+            })
+        })
+        .await?;
+
+    Ok(())
+}
+
+/// Which way a paginator is moving.
+#[derive(Debug, Clone, Copy)]
+enum Direction {
+    Prev,
+    Next,
+    Random,
+}
+
+/// Find the page of the first post that passes validation, starting at `page` and moving in
+/// `direction`. Posts that fail are removed. Returns `None` if no posts are left.
+async fn valid_page(
+    data: &Data,
+    posts: &mut Vec<QuickPost>,
+    mut page: usize,
+    direction: Direction,
+) -> Option<usize> {
+    while !posts.is_empty() {
+        if posts::validate(data, &posts[page]).await {
+            return Some(page);
+        }
+
+        posts.remove(page);
+        if posts.is_empty() {
+            break;
+        }
+        page = match direction {
+            // The next post moved into this page
+            Direction::Next => page % posts.len(),
+            Direction::Prev => (page + posts.len() - 1) % posts.len(),
+            Direction::Random => rand::thread_rng().gen_range(0..posts.len()),
+        };
+    }
+
+    None
+}
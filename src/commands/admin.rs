@@ -1,9 +1,17 @@
 //! Admin commands.
 
-use anyhow::Result;
+use std::cmp::Reverse;
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Result};
+use chrono::{DateTime, Utc};
 use poise::builtins::register_application_commands;
+use poise::futures_util::future;
+use poise::serenity_prelude::GuildId;
+use tracing::{error, info};
 
-use crate::Context;
+use crate::activity::Template;
+use crate::{commands, data, setup, Context};
 
 /// Replies with "Pong!". Only usable by bot owners.
 #[poise::command(prefix_command, owners_only, hide_in_help)]
@@ -20,3 +28,257 @@ pub async fn register(ctx: Context<'_>, #[flag] all: bool) -> Result<()> {
 
     Ok(())
 }
+
+/// Show the post cache status for each subreddit. Only usable by bot owners.
+#[poise::command(prefix_command, owners_only, hide_in_help)]
+pub async fn cache(ctx: Context<'_>) -> Result<()> {
+    let data = ctx.data();
+    let settings = data.settings.read().await.clone();
+    let mut groups = data::SUBS
+        .iter()
+        .map(|group| (group.key().clone(), group.value().clone()))
+        .collect::<Vec<_>>();
+    groups.sort_unstable();
+
+    let mut lines = Vec::new();
+    for (group, subs) in groups {
+        let disabled = if settings.is_disabled(&group) {
+            " (disabled)"
+        } else {
+            ""
+        };
+        lines.push(format!("{group}:{disabled}"));
+
+        for sub in subs {
+            let count = data.posts.get(&sub).map_or(0, |posts| posts.len());
+            let age = data.refreshed.get(&sub).map_or_else(
+                || "never".to_string(),
+                |time| format!("{} ago", format_age(*time)),
+            );
+            let stale = if data.is_stale(&sub) { " (stale)" } else { "" };
+
+            lines.push(format!("  r/{sub}: {count} posts, refreshed {age}{stale}"));
+        }
+    }
+
+    ctx.say(commands::code_block(&lines)).await?;
+
+    Ok(())
+}
+
+/// Refresh the cached posts of a subreddit, or of every subreddit. Only usable by bot owners.
+#[poise::command(prefix_command, owners_only, hide_in_help)]
+pub async fn refresh(ctx: Context<'_>, sub: Option<String>) -> Result<()> {
+    let data = ctx.data();
+
+    match sub {
+        Some(sub) => {
+            let name = sub.trim_start_matches("r/");
+            // Subreddits are cached under the name in their group, which may not be lowercase
+            let Some(sub) = data::all_subs()
+                .into_iter()
+                .find(|sub| sub.eq_ignore_ascii_case(name))
+            else {
+                bail!("r/{name} is not in any subreddit group");
+            };
+
+            let count = setup::hot_posts(
+                &sub,
+                data.posts.clone(),
+                data.refreshed.clone(),
+                data.index.clone(),
+                data.summaries.clone(),
+            )
+            .await?;
+            ctx.say(format!("Refreshed r/{sub}: {count} posts")).await?;
+        }
+        None => {
+            let timer = Instant::now();
+            let failed = setup::all_hot_posts(
+                data.posts.clone(),
+                data.refreshed.clone(),
+                data.index.clone(),
+                data.summaries.clone(),
+            )
+            .await;
+            ctx.say(format!(
+                "Refreshed {} subreddits in {}, {failed} failed",
+                data::all_subs().len(),
+                humantime::format_duration(Duration::from_secs(timer.elapsed().as_secs()))
+            ))
+            .await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Reload subreddit groups from `subs.json`. Only usable by bot owners.
+#[poise::command(prefix_command, owners_only, hide_in_help)]
+pub async fn reload(ctx: Context<'_>) -> Result<()> {
+    let data = ctx.data();
+    let before = data::all_subs();
+
+    data::set_subs(setup::subs_from_file()?);
+
+    let subs = data::all_subs();
+    let added = subs
+        .iter()
+        .filter(|sub| !before.contains(sub))
+        .collect::<Vec<_>>();
+
+    // Posts of removed subreddits are dropped, new ones are fetched right away
+    data.posts.retain(|sub, _| subs.contains(sub));
+    data.refreshed.retain(|sub, _| subs.contains(sub));
+    data.index.retain(|sub| subs.iter().any(|s| s == sub));
+    let failed = future::join_all(added.iter().map(|sub| {
+        setup::hot_posts(
+            sub,
+            data.posts.clone(),
+            data.refreshed.clone(),
+            data.index.clone(),
+            data.summaries.clone(),
+        )
+    }))
+    .await
+    .into_iter()
+    .filter_map(Result::err)
+    .inspect(|e| error!("{e:#}"))
+    .count();
+
+    ctx.say(format!(
+        "Reloaded {} groups with {} subreddits ({} added, {} removed, {failed} failed to load)",
+        data::SUBS.len(),
+        subs.len(),
+        added.len(),
+        before.iter().filter(|sub| !subs.contains(sub)).count()
+    ))
+    .await?;
+
+    Ok(())
+}
+
+/// List the servers the bot is in, by member count. Only usable by bot owners.
+#[poise::command(prefix_command, owners_only, hide_in_help)]
+pub async fn guilds(ctx: Context<'_>) -> Result<()> {
+    let cache = &ctx.discord().cache;
+    let mut guilds = cache
+        .guilds()
+        .into_iter()
+        .filter_map(|id| cache.guild(id))
+        .map(|guild| (guild.member_count, guild.id, guild.name))
+        .collect::<Vec<_>>();
+    guilds.sort_unstable_by_key(|(members, ..)| Reverse(*members));
+
+    let mut lines = vec![format!("{} servers", guilds.len())];
+    lines.extend(
+        guilds
+            .into_iter()
+            .map(|(members, id, name)| format!("{id} {members:>8} {name}")),
+    );
+
+    ctx.say(commands::code_block(&lines)).await?;
+
+    Ok(())
+}
+
+/// Leave a server. Only usable by bot owners.
+#[poise::command(prefix_command, owners_only, hide_in_help)]
+pub async fn leave(ctx: Context<'_>, guild: u64) -> Result<()> {
+    let guild = GuildId(guild);
+    let name = guild
+        .name(ctx.discord())
+        .unwrap_or_else(|| guild.to_string());
+
+    guild.leave(ctx.discord()).await?;
+    info!(%guild, "left server");
+    ctx.say(format!("Left {name}")).await?;
+
+    Ok(())
+}
+
+/// Change the bot's activity until restart, e.g. `activity watching {guilds} servers`. Streaming
+/// activities take the stream URL first. Only usable by bot owners.
+#[poise::command(prefix_command, owners_only, hide_in_help)]
+pub async fn activity(ctx: Context<'_>, #[rest] activity: String) -> Result<()> {
+    let template = activity.parse::<Template>()?;
+
+    ctx.data()
+        .presence
+        .set_templates(ctx.discord(), vec![template])
+        .await;
+    ctx.say("Activity updated").await?;
+
+    Ok(())
+}
+
+/// Toggle maintenance mode, or set it with `on` / `off` and an optional message. While enabled,
+/// commands from anyone other than bot owners are rejected. Only usable by bot owners.
+#[poise::command(prefix_command, owners_only, hide_in_help)]
+pub async fn maintenance(
+    ctx: Context<'_>,
+    enabled: Option<bool>,
+    #[rest] message: Option<String>,
+) -> Result<()> {
+    let settings = ctx
+        .data()
+        .update_settings(|settings| {
+            settings.maintenance = enabled.unwrap_or(!settings.maintenance);
+            if message.is_some() {
+                settings.maintenance_message = message;
+            }
+        })
+        .await?;
+
+    ctx.data().presence.update(ctx.discord()).await;
+    info!(enabled = settings.maintenance, "maintenance mode changed");
+    ctx.say(if settings.maintenance {
+        format!(
+            "Maintenance mode enabled: {}",
+            settings.maintenance_message()
+        )
+    } else {
+        "Maintenance mode disabled".to_string()
+    })
+    .await?;
+
+    Ok(())
+}
+
+/// Disable a subreddit group everywhere, or enable it again with `off`. Only usable by bot owners.
+#[poise::command(prefix_command, owners_only, hide_in_help)]
+pub async fn killswitch(ctx: Context<'_>, group: String, disabled: Option<bool>) -> Result<()> {
+    let group = group.to_lowercase();
+    if !data::SUBS.contains_key(&group) {
+        bail!("There is no subreddit group named {group}");
+    }
+
+    let settings = ctx
+        .data()
+        .update_settings(|settings| {
+            let disabled = disabled.unwrap_or_else(|| !settings.is_disabled(&group));
+
+            settings.disabled_groups.retain(|name| *name != group);
+            if disabled {
+                settings.disabled_groups.push(group.clone());
+            }
+        })
+        .await?;
+
+    let disabled = settings.is_disabled(&group);
+    info!(group, disabled, "group kill switch changed");
+    ctx.say(format!(
+        "The {group} group is {}",
+        if disabled { "disabled" } else { "enabled" }
+    ))
+    .await?;
+
+    Ok(())
+}
+
+/// Time elapsed since `time`, rounded to seconds.
+fn format_age(time: DateTime<Utc>) -> String {
+    let age = (Utc::now() - time).to_std().unwrap_or_default();
+
+    humantime::format_duration(Duration::from_secs(age.as_secs())).to_string()
+}
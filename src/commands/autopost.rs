@@ -0,0 +1,127 @@
+//! Scheduled post commands.
+
+use anyhow::{anyhow, bail, Result};
+use chrono::Utc;
+
+use crate::autopost::Schedule;
+use crate::commands::{self, autocomplete_group};
+use crate::db::{self, Autopost};
+use crate::{posts, Context};
+
+/// Post from a subreddit group in a channel on a schedule.
+#[poise::command(
+    slash_command,
+    guild_only,
+    required_permissions = "MANAGE_CHANNELS",
+    subcommands("set", "list", "cancel")
+)]
+pub async fn autopost(_ctx: Context<'_>) -> Result<()> {
+    Ok(())
+}
+
+/// Post from a subreddit group in this channel on a schedule.
+#[poise::command(slash_command, guild_only, required_permissions = "MANAGE_CHANNELS")]
+pub async fn set(
+    ctx: Context<'_>,
+    #[description = "Subreddit group to post from"]
+    #[autocomplete = "autocomplete_group"]
+    group: String,
+    #[description = "`every <duration>`, e.g. `every 1h`, or a cron expression with seconds"]
+    schedule: String,
+) -> Result<()> {
+    let data = ctx.data();
+    let channel = ctx.channel_id();
+    let group = group.to_lowercase();
+    let next = schedule
+        .parse::<Schedule>()?
+        .next(Utc::now())
+        .ok_or_else(|| anyhow!("That schedule has no upcoming times."))?;
+
+    // Fail now if nothing could ever be posted here, e.g. an NSFW group in an SFW channel
+    let target = posts::target(ctx.discord(), data, channel).await?;
+    posts::candidates(data, &target, &group).await?;
+
+    let autopost = Autopost {
+        channel_id: channel,
+        group: group.clone(),
+        schedule: schedule.trim().to_string(),
+        next,
+    };
+    db::save_autopost(&data.db, &autopost).await?;
+    data.autoposts.insert((channel, group.clone()), autopost);
+
+    ctx.say(format!(
+        "Posting from {group} in this channel `{}`, starting <t:{}:R>.",
+        schedule.trim(),
+        next.timestamp()
+    ))
+    .await?;
+
+    Ok(())
+}
+
+/// List the scheduled posts in this server.
+#[poise::command(slash_command, guild_only, required_permissions = "MANAGE_CHANNELS")]
+pub async fn list(ctx: Context<'_>) -> Result<()> {
+    let guild = ctx
+        .guild()
+        .ok_or_else(|| anyhow!("This server isn't cached yet, try again in a moment!"))?;
+    let mut autoposts = ctx
+        .data()
+        .autoposts
+        .iter()
+        .filter(|autopost| guild.channels.contains_key(&autopost.channel_id))
+        .map(|autopost| autopost.value().clone())
+        .collect::<Vec<_>>();
+    autoposts.sort_unstable_by_key(|autopost| autopost.next);
+
+    if autoposts.is_empty() {
+        ctx.say("There are no scheduled posts in this server.")
+            .await?;
+        return Ok(());
+    }
+
+    ctx.say(commands::join_lines(
+        &autoposts
+            .iter()
+            .map(|autopost| {
+                format!(
+                    "<#{}> {} `{}`, next <t:{}:R>",
+                    autopost.channel_id,
+                    autopost.group,
+                    autopost.schedule,
+                    autopost.next.timestamp()
+                )
+            })
+            .collect::<Vec<_>>(),
+    ))
+    .await?;
+
+    Ok(())
+}
+
+/// Cancel a scheduled post in this channel.
+#[poise::command(slash_command, guild_only, required_permissions = "MANAGE_CHANNELS")]
+pub async fn cancel(
+    ctx: Context<'_>,
+    #[description = "Subreddit group to stop posting from"]
+    #[autocomplete = "autocomplete_group"]
+    group: String,
+) -> Result<()> {
+    let data = ctx.data();
+    let channel = ctx.channel_id();
+    let group = group.to_lowercase();
+
+    if !data.autoposts.contains_key(&(channel, group.clone())) {
+        bail!("There are no scheduled {group} posts in this channel.");
+    }
+
+    db::delete_autopost(&data.db, channel, &group).await?;
+    data.autoposts.remove(&(channel, group.clone()));
+    ctx.say(format!(
+        "Cancelled scheduled {group} posts in this channel."
+    ))
+    .await?;
+
+    Ok(())
+}
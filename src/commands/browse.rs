@@ -0,0 +1,26 @@
+//! Post browsing commands.
+
+use anyhow::Result;
+
+use crate::commands::{autocomplete_source, paginate};
+use crate::data::Kind;
+use crate::{posts, Context};
+
+/// Scroll through the cached posts of a subreddit group or subreddit.
+#[poise::command(slash_command)]
+pub async fn browse(
+    ctx: Context<'_>,
+    #[description = "Subreddit group or subreddit to browse"]
+    #[autocomplete = "autocomplete_source"]
+    name: String,
+    #[description = "Kind of post (default this channel's)"]
+    #[rename = "type"]
+    kind: Option<Kind>,
+) -> Result<()> {
+    let target = posts::target(ctx.discord(), ctx.data(), ctx.channel_id())
+        .await?
+        .with_kind(kind);
+    let posts = posts::candidates(ctx.data(), &target, &name.to_lowercase()).await?;
+
+    paginate(ctx, posts).await
+}
@@ -0,0 +1,145 @@
+//! Daily digest commands.
+
+use anyhow::{anyhow, bail, Result};
+use chrono::Utc;
+
+use crate::commands::{self, autocomplete_group};
+use crate::db::{self, Digest};
+use crate::digest::{self, MAX_COUNT};
+use crate::{posts, Context};
+
+/// Default number of posts in a digest.
+const COUNT: u32 = 5;
+
+/// Send a daily digest of a subreddit group's top posts to a channel.
+#[poise::command(
+    slash_command,
+    guild_only,
+    required_permissions = "MANAGE_CHANNELS",
+    subcommands("set", "list", "cancel")
+)]
+pub async fn digest(_ctx: Context<'_>) -> Result<()> {
+    Ok(())
+}
+
+/// Send a daily digest of a subreddit group's top posts to this channel.
+#[poise::command(slash_command, guild_only, required_permissions = "MANAGE_CHANNELS")]
+pub async fn set(
+    ctx: Context<'_>,
+    #[description = "Subreddit group to get top posts from"]
+    #[autocomplete = "autocomplete_group"]
+    group: String,
+    #[description = "Time of day to send the digest at, e.g. 09:00"] time: String,
+    #[description = "Timezone, e.g. Europe/London (default UTC)"] timezone: Option<String>,
+    #[description = "Number of posts (default 5)"]
+    #[min = 1]
+    #[max = 10]
+    count: Option<u32>,
+) -> Result<()> {
+    let data = ctx.data();
+    let channel = ctx.channel_id();
+    let group = group.to_lowercase();
+    let timezone = timezone.unwrap_or_else(|| "UTC".to_string());
+    let count = count.unwrap_or(COUNT).clamp(1, MAX_COUNT);
+    let next = digest::next(
+        digest::parse_time(&time)?,
+        digest::parse_timezone(&timezone)?,
+        Utc::now(),
+    );
+
+    posts::subs(data, channel, &group).await?;
+
+    // Keep the posts sent by a digest being replaced, so they still aren't repeated
+    let sent = data
+        .digests
+        .get(&(channel, group.clone()))
+        .map(|digest| digest.sent.clone())
+        .unwrap_or_default();
+    let digest = Digest {
+        channel_id: channel,
+        group: group.clone(),
+        time: time.trim().to_string(),
+        timezone: timezone.trim().to_string(),
+        count,
+        next,
+        sent,
+    };
+    db::save_digest(&data.db, &digest).await?;
+    data.digests.insert((channel, group.clone()), digest);
+
+    ctx.say(format!(
+        "Sending the top {count} {group} posts to this channel daily, starting <t:{}:R>.",
+        next.timestamp()
+    ))
+    .await?;
+
+    Ok(())
+}
+
+/// List the daily digests in this server.
+#[poise::command(slash_command, guild_only, required_permissions = "MANAGE_CHANNELS")]
+pub async fn list(ctx: Context<'_>) -> Result<()> {
+    let guild = ctx
+        .guild()
+        .ok_or_else(|| anyhow!("This server isn't cached yet, try again in a moment!"))?;
+    let mut digests = ctx
+        .data()
+        .digests
+        .iter()
+        .filter(|digest| guild.channels.contains_key(&digest.channel_id))
+        .map(|digest| digest.value().clone())
+        .collect::<Vec<_>>();
+    digests.sort_unstable_by_key(|digest| digest.next);
+
+    if digests.is_empty() {
+        ctx.say("There are no daily digests in this server.")
+            .await?;
+        return Ok(());
+    }
+
+    ctx.say(commands::join_lines(
+        &digests
+            .iter()
+            .map(|digest| {
+                format!(
+                    "<#{}> top {} {} at {} {}, next <t:{}:R>",
+                    digest.channel_id,
+                    digest.count,
+                    digest.group,
+                    digest.time,
+                    digest.timezone,
+                    digest.next.timestamp()
+                )
+            })
+            .collect::<Vec<_>>(),
+    ))
+    .await?;
+
+    Ok(())
+}
+
+/// Cancel a daily digest in this channel.
+#[poise::command(slash_command, guild_only, required_permissions = "MANAGE_CHANNELS")]
+pub async fn cancel(
+    ctx: Context<'_>,
+    #[description = "Subreddit group of the digest"]
+    #[autocomplete = "autocomplete_group"]
+    group: String,
+) -> Result<()> {
+    let data = ctx.data();
+    let channel = ctx.channel_id();
+    let group = group.to_lowercase();
+
+    if !data.digests.contains_key(&(channel, group.clone())) {
+        bail!("There is no daily {group} digest in this channel.");
+    }
+
+    db::delete_digest(&data.db, channel, &group).await?;
+    data.digests.remove(&(channel, group.clone()));
+    ctx.say(format!(
+        "Cancelled the daily {group} digest in this channel."
+    ))
+    .await?;
+
+    Ok(())
+}
@@ -0,0 +1,232 @@
+//! Post filter commands.
+
+use anyhow::{anyhow, bail, Result};
+
+use crate::db::{self, Filters};
+use crate::filter::{self, Filter};
+use crate::Context;
+
+/// Maximum number of keywords, regexes, flairs or domains in each list.
+const MAX_RULES: usize = 50;
+/// Maximum length of a keyword, regex, flair or domain.
+const MAX_RULE_LEN: usize = 100;
+
+/// A list of post filter rules.
+#[derive(Debug, Clone, Copy, poise::ChoiceParameter)]
+pub enum Rule {
+    #[name = "blocked keyword"]
+    Keyword,
+    #[name = "blocked regex"]
+    Regex,
+    #[name = "blocked flair"]
+    Flair,
+    #[name = "required flair"]
+    RequiredFlair,
+    #[name = "allowed news domain"]
+    AllowedDomain,
+    #[name = "blocked news domain"]
+    BlockedDomain,
+}
+
+impl Rule {
+    const fn name(self) -> &'static str {
+        match self {
+            Self::Keyword => "blocked keyword",
+            Self::Regex => "blocked regex",
+            Self::Flair => "blocked flair",
+            Self::RequiredFlair => "required flair",
+            Self::AllowedDomain => "allowed news domain",
+            Self::BlockedDomain => "blocked news domain",
+        }
+    }
+
+    const fn list(self, filters: &mut Filters) -> &mut Vec<String> {
+        match self {
+            Self::Keyword => &mut filters.blocked_keywords,
+            Self::Regex => &mut filters.blocked_patterns,
+            Self::Flair => &mut filters.blocked_flairs,
+            Self::RequiredFlair => &mut filters.required_flairs,
+            Self::AllowedDomain => &mut filters.allowed_domains,
+            Self::BlockedDomain => &mut filters.blocked_domains,
+        }
+    }
+}
+
+/// Filter the posts sent in this server.
+#[poise::command(
+    slash_command,
+    guild_only,
+    required_permissions = "MANAGE_GUILD",
+    subcommands("show", "score", "spoilers", "add", "remove")
+)]
+pub async fn filters(_ctx: Context<'_>) -> Result<()> {
+    Ok(())
+}
+
+/// Show the post filters in this server.
+#[poise::command(slash_command, guild_only, required_permissions = "MANAGE_GUILD")]
+pub async fn show(ctx: Context<'_>) -> Result<()> {
+    let filters = current(ctx)?;
+    let list = |rules: &[String]| {
+        if rules.is_empty() {
+            "none".to_string()
+        } else {
+            rules
+                .iter()
+                .map(|rule| format!("`{rule}`"))
+                .collect::<Vec<_>>()
+                .join(", ")
+        }
+    };
+
+    ctx.say(format!(
+        "**Minimum score:** {}\n**Spoilers:** {}\n**Blocked keywords:** {}\n\
+         **Blocked regexes:** {}\n**Blocked flairs:** {}\n**Required flairs:** {}\n\
+         **Allowed news domains:** {}\n**Blocked news domains:** {}",
+        filters
+            .min_score
+            .map_or_else(|| "none".to_string(), |min| min.to_string()),
+        if filters.block_spoilers {
+            "blocked"
+        } else {
+            "allowed"
+        },
+        list(&filters.blocked_keywords),
+        list(&filters.blocked_patterns),
+        list(&filters.blocked_flairs),
+        list(&filters.required_flairs),
+        list(&filters.allowed_domains),
+        list(&filters.blocked_domains),
+    ))
+    .await?;
+
+    Ok(())
+}
+
+/// Set the minimum score of posts sent in this server.
+#[poise::command(slash_command, guild_only, required_permissions = "MANAGE_GUILD")]
+pub async fn score(
+    ctx: Context<'_>,
+    #[description = "Minimum score (default none)"] min: Option<i64>,
+) -> Result<()> {
+    update(ctx, |filters| {
+        filters.min_score = min.map(|min| min as f64);
+        Ok(())
+    })
+    .await?;
+
+    ctx.say(min.map_or_else(
+        || "Posts with any score will be sent.".to_string(),
+        |min| format!("Only posts with {min} points or more will be sent."),
+    ))
+    .await?;
+
+    Ok(())
+}
+
+/// Allow or block posts marked as spoilers in this server.
+#[poise::command(slash_command, guild_only, required_permissions = "MANAGE_GUILD")]
+pub async fn spoilers(
+    ctx: Context<'_>,
+    #[description = "Whether spoilers are allowed"] allowed: bool,
+) -> Result<()> {
+    update(ctx, |filters| {
+        filters.block_spoilers = !allowed;
+        Ok(())
+    })
+    .await?;
+
+    ctx.say(if allowed {
+        "Spoilers are allowed."
+    } else {
+        "Spoilers are blocked."
+    })
+    .await?;
+
+    Ok(())
+}
+
+/// Add a post filter rule in this server.
+#[poise::command(slash_command, guild_only, required_permissions = "MANAGE_GUILD")]
+pub async fn add(
+    ctx: Context<'_>,
+    #[description = "Kind of rule"] rule: Rule,
+    #[description = "Keyword, regex, flair or domain; case insensitive"] value: String,
+) -> Result<()> {
+    let value = value.trim().to_string();
+    if value.is_empty() || value.chars().count() > MAX_RULE_LEN {
+        bail!("Rules must be between 1 and {MAX_RULE_LEN} characters long.");
+    }
+    if matches!(rule, Rule::Regex) {
+        filter::compile(&value)?;
+    }
+
+    update(ctx, |filters| {
+        let list = rule.list(filters);
+        if list.iter().any(|v| v.eq_ignore_ascii_case(&value)) {
+            bail!("`{value}` is already a {}.", rule.name());
+        }
+        if list.len() >= MAX_RULES {
+            bail!("There can be at most {MAX_RULES} of each kind of rule.");
+        }
+        list.push(value.clone());
+        Ok(())
+    })
+    .await?;
+
+    ctx.say(format!("Added {} `{value}`.", rule.name())).await?;
+
+    Ok(())
+}
+
+/// Remove a post filter rule in this server.
+#[poise::command(slash_command, guild_only, required_permissions = "MANAGE_GUILD")]
+pub async fn remove(
+    ctx: Context<'_>,
+    #[description = "Kind of rule"] rule: Rule,
+    #[description = "Keyword, regex, flair or domain to remove"] value: String,
+) -> Result<()> {
+    let value = value.trim().to_string();
+
+    update(ctx, |filters| {
+        let list = rule.list(filters);
+        let len = list.len();
+        list.retain(|v| !v.eq_ignore_ascii_case(&value));
+        if list.len() == len {
+            bail!("`{value}` isn't a {}.", rule.name());
+        }
+        Ok(())
+    })
+    .await?;
+
+    ctx.say(format!("Removed {} `{value}`.", rule.name()))
+        .await?;
+
+    Ok(())
+}
+
+/// The post filters in the current guild.
+fn current(ctx: Context<'_>) -> Result<Filters> {
+    let guild = ctx
+        .guild_id()
+        .ok_or_else(|| anyhow!("Filters can only be used in servers."))?;
+
+    Ok(ctx
+        .data()
+        .filters
+        .get(&guild)
+        .map_or_else(|| Filters::new(guild), |filter| filter.config.clone()))
+}
+
+/// Change the post filters in the current guild, and save them.
+async fn update(ctx: Context<'_>, f: impl FnOnce(&mut Filters) -> Result<()>) -> Result<()> {
+    let data = ctx.data();
+    let mut filters = current(ctx)?;
+    f(&mut filters)?;
+
+    let filter = Filter::new(filters)?;
+    db::save_filters(&data.db, &filter.config).await?;
+    data.filters.insert(filter.config.guild_id, filter);
+
+    Ok(())
+}
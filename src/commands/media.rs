@@ -0,0 +1,65 @@
+//! Channel media limit commands.
+
+use anyhow::{bail, Result};
+
+use crate::data::Kind;
+use crate::db::{self, ChannelMedia};
+use crate::Context;
+
+/// Limit the kind of post sent in a channel.
+#[poise::command(
+    slash_command,
+    guild_only,
+    required_permissions = "MANAGE_CHANNELS",
+    subcommands("set", "clear")
+)]
+pub async fn media(_ctx: Context<'_>) -> Result<()> {
+    Ok(())
+}
+
+/// Only send one kind of post in this channel, e.g. images.
+#[poise::command(slash_command, guild_only, required_permissions = "MANAGE_CHANNELS")]
+pub async fn set(
+    ctx: Context<'_>,
+    #[description = "Kind of post"]
+    #[rename = "type"]
+    kind: Kind,
+) -> Result<()> {
+    let data = ctx.data();
+    let channel = ctx.channel_id();
+
+    db::save_media(
+        &data.db,
+        &ChannelMedia {
+            channel_id: channel,
+            kind,
+        },
+    )
+    .await?;
+    data.media.insert(channel, kind);
+
+    ctx.say(format!(
+        "Only {kind} posts will be sent in this channel, unless a command asks for another type."
+    ))
+    .await?;
+
+    Ok(())
+}
+
+/// Send every kind of post in this channel.
+#[poise::command(slash_command, guild_only, required_permissions = "MANAGE_CHANNELS")]
+pub async fn clear(ctx: Context<'_>) -> Result<()> {
+    let data = ctx.data();
+    let channel = ctx.channel_id();
+
+    if !data.media.contains_key(&channel) {
+        bail!("This channel isn't limited to a kind of post.");
+    }
+
+    db::delete_media(&data.db, channel).await?;
+    data.media.remove(&channel);
+    ctx.say("Every kind of post will be sent in this channel.")
+        .await?;
+
+    Ok(())
+}
@@ -0,0 +1,54 @@
+//! Post search commands.
+
+use anyhow::{bail, Result};
+
+use crate::commands::{autocomplete_group, paginate};
+use crate::data::{self, Kind};
+use crate::{posts, Context};
+
+/// Search the titles of cached posts, and the text of text posts.
+#[poise::command(slash_command)]
+pub async fn search(
+    ctx: Context<'_>,
+    #[description = "Keywords to search for"] query: String,
+    #[description = "Subreddit group to search (default all)"]
+    #[autocomplete = "autocomplete_group"]
+    group: Option<String>,
+    #[description = "Kind of post (default this channel's)"]
+    #[rename = "type"]
+    kind: Option<Kind>,
+) -> Result<()> {
+    let data = ctx.data();
+    let channel = ctx.channel_id();
+
+    let subs = match group {
+        Some(group) => posts::subs(data, channel, &group.to_lowercase()).await?,
+        None => {
+            // Skip groups that are disabled or fully banned in this channel
+            let groups = data::SUBS
+                .iter()
+                .map(|group| group.key().clone())
+                .collect::<Vec<_>>();
+            let mut subs = Vec::new();
+            for group in groups {
+                if let Ok(group_subs) = posts::subs(data, channel, &group).await {
+                    subs.extend(group_subs);
+                }
+            }
+            subs.sort_unstable();
+            subs.dedup();
+            subs
+        }
+    };
+
+    let target = posts::target(ctx.discord(), data, channel)
+        .await?
+        .with_kind(kind);
+    let mut results = posts::allowed(data, &target, data.index.search(&data.posts, &subs, &query));
+    data::dedup(&mut results);
+    if results.is_empty() {
+        bail!("No posts found for `{query}`.");
+    }
+
+    paginate(ctx, results).await
+}
@@ -1,9 +1,12 @@
 //! Bot runtime data.
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::fmt::{self, Display};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use anyhow::Result;
+use anyhow::{bail, Result};
 use chrono::{DateTime, Utc};
 use dashmap::mapref::entry::Entry;
 use dashmap::DashMap;
@@ -12,14 +15,30 @@ use governor::state::keyed::DefaultKeyedStateStore;
 use governor::RateLimiter;
 use mongodb::bson::doc;
 use mongodb::{Client, Database};
-use once_cell::sync::OnceCell;
-use poise::serenity_prelude::ChannelId;
+use once_cell::sync::Lazy;
+use poise::serenity_prelude::{ChannelId, GuildId, RwLock};
+use reqwest::Url;
 use roux::subreddit::responses::Submissions;
 
-use crate::db::{Channel, ChannelInfo};
+use crate::activity::Presence;
+use crate::db::{self, Autopost, Channel, ChannelInfo, Digest, Settings};
+use crate::filter::Filter;
+use crate::hash::Hashes;
+use crate::news::Summaries;
+use crate::search::Index;
+use crate::shutdown::Shutdown;
+use crate::validate::Validator;
+use crate::{metrics, setup};
+
+/// Group of subreddits with text posts.
+pub const TEXT_GROUP: &str = "text";
+/// Group of subreddits with news articles.
+pub const NEWS_GROUP: &str = "news";
+/// Group of subreddits with 50/50 posts.
+pub const FIFTYFIFTY_GROUP: &str = "fiftyfifty";
 
 /// Map of subreddit groups and subreddit names from `subs.json`.
-pub static SUBS: OnceCell<HashMap<String, Vec<String>>> = OnceCell::new();
+pub static SUBS: Lazy<DashMap<String, Vec<String>>> = Lazy::new(DashMap::new);
 
 /// Bot runtime data.
 #[derive(Debug)]
@@ -43,13 +62,49 @@ pub struct Data {
 
     /// Map of subreddit names and their top 100 hot posts.
     pub posts: Arc<DashMap<String, Vec<QuickPost>>>,
+    /// Search index of the cached posts.
+    pub index: Arc<Index>,
+    /// Summaries of cached news articles.
+    pub summaries: Arc<Summaries>,
+    /// Checks that cached posts still exist before they're sent.
+    pub validator: Validator,
+    /// Perceptual hashes of cached posts' images.
+    pub hashes: Hashes,
+    /// Map of subreddit names and the last time their posts were fetched.
+    pub refreshed: Arc<DashMap<String, DateTime<Utc>>>,
+    /// Age after which a subreddit's cached posts are stale.
+    pub stale_after: Duration,
 
     /// Map of discord channel IDs the bot is active in, and the channels' names and nsfw statuses.
     pub channels: Arc<DashMap<ChannelId, ChannelInfo>>,
     /// Map of discord channel IDs and blacklisted reddit posts.
-    pub blacklist: Arc<DashMap<ChannelId, Vec<QuickPost>>>,
+    pub blacklist: Arc<DashMap<ChannelId, Vec<Blacklisted>>>,
     /// Map of discord channel IDs and their last post.
     pub last_post: Arc<DashMap<ChannelId, QuickPost>>,
+    /// Map of discord channel IDs and their banned subreddits (lowercase).
+    pub bans: Arc<DashMap<ChannelId, Vec<String>>>,
+    /// Map of discord channel IDs and subreddit groups, and their scheduled posts.
+    pub autoposts: Arc<DashMap<(ChannelId, String), Autopost>>,
+    /// Map of discord channel IDs and subreddit groups, and their daily digests.
+    pub digests: Arc<DashMap<(ChannelId, String), Digest>>,
+    /// Map of discord channel IDs and the kind of post they're limited to.
+    pub media: Arc<DashMap<ChannelId, Kind>>,
+    /// Map of discord guild IDs and their post filters.
+    pub filters: Arc<DashMap<GuildId, Filter>>,
+    /// Queues blacklist and last post writes to the database.
+    pub writer: db::Writer,
+
+    /// Settings changed at runtime by bot owners, loaded from the database on startup.
+    pub settings: Arc<RwLock<Settings>>,
+    /// The bot's presence.
+    pub presence: Arc<Presence>,
+    /// Number of posts sent since startup.
+    pub memes_served: Arc<AtomicU64>,
+
+    /// Readiness of subsystems that start in the background.
+    pub ready: Arc<Readiness>,
+    /// Shutdown state and in-flight command tracking.
+    pub shutdown: Arc<Shutdown>,
 
     /// Request rate limiter keyed by discord channel ID.
     pub governor: Arc<RateLimiter<ChannelId, DefaultKeyedStateStore<ChannelId>, QuantaUpkeepClock>>,
@@ -57,8 +112,61 @@ pub struct Data {
     pub clock: QuantaUpkeepClock,
 }
 
+/// A subsystem that starts in the background.
+#[derive(Debug, Clone, Copy)]
+pub enum Subsystem {
+    Commands,
+    Posts,
+    Database,
+}
+
+impl Display for Subsystem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Commands => "commands",
+            Self::Posts => "posts",
+            Self::Database => "database",
+        })
+    }
+}
+
+/// Readiness of subsystems that start in the background.
+#[derive(Debug, Default)]
+pub struct Readiness([AtomicBool; 3]);
+
+impl Readiness {
+    /// Mark a subsystem as ready.
+    pub fn set(&self, subsystem: Subsystem) {
+        self.0[subsystem as usize].store(true, Ordering::Release);
+        tracing::info!("{subsystem} ready");
+    }
+
+    /// Whether a subsystem is ready.
+    pub fn get(&self, subsystem: Subsystem) -> bool {
+        self.0[subsystem as usize].load(Ordering::Acquire)
+    }
+}
+
+/// A post blacklisted in a channel.
+#[derive(Debug, Clone)]
+pub struct Blacklisted {
+    /// The post's key, shared by its duplicates.
+    pub key: String,
+    /// When the post was sent to the channel.
+    pub time: DateTime<Utc>,
+}
+
+impl Blacklisted {
+    /// Whether the post has been blacklisted for longer than `BLACKLIST_TTL`.
+    pub fn is_expired(&self) -> bool {
+        (Utc::now() - self.time)
+            .to_std()
+            .is_ok_and(|age| age > db::BLACKLIST_TTL)
+    }
+}
+
 /// Specific data for a reddit post.
-#[derive(Debug)]
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub struct QuickPost {
     pub title: String,
     pub score: f64,
@@ -66,27 +174,228 @@ pub struct QuickPost {
     pub nsfw: bool,
     pub permalink: String,
     pub sub: String,
+    /// Link flair text.
+    #[serde(default)]
+    pub flair: Option<String>,
+    /// Whether the post is marked as a spoiler.
+    #[serde(default)]
+    pub spoiler: bool,
+    /// What the post's content is.
+    #[serde(default)]
+    pub kind: Kind,
+    /// Domain the post links to, or `self.<sub>` for self posts.
+    #[serde(default)]
+    pub domain: String,
+    /// When the post was created, as a unix timestamp.
+    #[serde(default)]
+    pub created: i64,
+    /// Key shared by duplicates of the post, e.g. crossposts and reposts of the same link.
+    #[serde(default)]
+    pub key: String,
+}
+
+impl QuickPost {
+    /// Set the post's key if it was stored before posts had keys.
+    pub fn ensure_key(&mut self) {
+        if self.key.is_empty() {
+            self.key = self.canonical_key();
+        }
+    }
+
+    /// The image the post links to, if it can be shown in an embed. GIFs can only be embedded if
+    /// they link to the file rather than to a player.
+    pub fn image(&self) -> Option<&str> {
+        let embeddable = match self.kind {
+            Kind::Image => true,
+            Kind::Gif => extension(&self.content) == "gif",
+            _ => false,
+        };
+
+        (embeddable && self.content.starts_with("https://")).then_some(self.content.as_str())
+    }
+
+    /// The key shared by duplicates of the post. Crossposts link to their parent post, which is
+    /// used as the key since reddit's API client doesn't expose the crosspost parent's ID.
+    /// Otherwise media and links are keyed by their normalized URL, and self posts by their ID.
+    fn canonical_key(&self) -> String {
+        if self.kind != Kind::Text {
+            if let Some(id) = reddit_post_id(&self.content) {
+                return format!("reddit:{id}");
+            }
+            if let Some(url) = normalize_url(&self.content, self.kind) {
+                return url;
+            }
+        }
+
+        reddit_post_id(&self.permalink)
+            .map_or_else(|| self.permalink.clone(), |id| format!("reddit:{id}"))
+    }
+}
+
+/// Kind of content a post has.
+#[derive(
+    Debug,
+    Default,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    serde::Serialize,
+    serde::Deserialize,
+    poise::ChoiceParameter,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum Kind {
+    #[name = "image"]
+    Image,
+    #[name = "gif"]
+    Gif,
+    #[name = "video"]
+    Video,
+    /// Self-text.
+    #[name = "text"]
+    Text,
+    /// A link to anything else, e.g. an article.
+    #[default]
+    #[name = "link"]
+    Link,
+}
+
+impl Display for Kind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Image => "image",
+            Self::Gif => "gif",
+            Self::Video => "video",
+            Self::Text => "text",
+            Self::Link => "link",
+        })
+    }
+}
+
+impl Kind {
+    /// Classify a post by its URL and the domain it links to. Reddit's API doesn't say what a post
+    /// links to, so this goes by file extension and well-known media hosts.
+    pub fn classify(is_self: bool, domain: &str, url: &str) -> Self {
+        if is_self {
+            return Self::Text;
+        }
+
+        let domain = domain.trim_start_matches("www.").to_lowercase();
+
+        match extension(url).as_str() {
+            "gif" | "gifv" => Self::Gif,
+            "mp4" | "webm" | "mov" => Self::Video,
+            "jpg" | "jpeg" | "png" | "webp" => Self::Image,
+            _ => match domain.as_str() {
+                "gfycat.com" | "redgifs.com" | "giphy.com" => Self::Gif,
+                "v.redd.it" | "youtube.com" | "youtu.be" | "streamable.com" => Self::Video,
+                "i.redd.it" | "i.imgur.com" => Self::Image,
+                _ => Self::Link,
+            },
+        }
+    }
 }
 
 impl Data {
     /// Add `QuickPost`s to the cache.
     pub fn add_posts(&mut self, sub: String, posts: Vec<QuickPost>) {
-        match self.posts.entry(sub) {
-            Entry::Occupied(ref mut entry) => entry.get_mut().extend(posts),
+        match self.posts.entry(sub.clone()) {
+            Entry::Occupied(ref mut entry) => {
+                entry.get_mut().extend(posts);
+                self.index.update(&sub, entry.get());
+            }
             Entry::Vacant(entry) => {
-                entry.insert(posts);
+                let cached = entry.insert(posts);
+                self.index.update(&sub, &cached);
             }
         }
     }
 
-    /// Add a `QuickPost` to the blacklist.
-    pub fn add_blacklist(&mut self, channel: ChannelId, post: QuickPost) {
-        match self.blacklist.entry(channel) {
-            Entry::Occupied(ref mut entry) => entry.get_mut().push(post),
-            Entry::Vacant(entry) => {
-                entry.insert(vec![post]);
+    /// Remove a post from the cache, e.g. after it was removed from reddit.
+    pub fn remove_post(&self, post: &QuickPost) {
+        // Cached subreddit names may not match the case reddit uses
+        for mut entry in self.posts.iter_mut() {
+            if entry.key().eq_ignore_ascii_case(&post.sub) {
+                entry.retain(|cached| cached.permalink != post.permalink);
+                self.index.update(entry.key(), entry.value());
+            }
+        }
+    }
+
+    /// Whether a subreddit's cached posts are missing or older than `stale_after`.
+    pub fn is_stale(&self, sub: &str) -> bool {
+        is_stale(&self.refreshed, sub, self.stale_after)
+    }
+
+    /// Make sure a subreddit's posts are cached, fetching them on demand if they haven't been
+    /// loaded yet.
+    pub async fn load_sub(&self, sub: &str) -> Result<()> {
+        if !self.posts.contains_key(sub) {
+            if let Err(e) = setup::hot_posts(
+                sub,
+                self.posts.clone(),
+                self.refreshed.clone(),
+                self.index.clone(),
+                self.summaries.clone(),
+            )
+            .await
+            {
+                tracing::error!("{e:#}");
             }
         }
+
+        if self.posts.contains_key(sub) {
+            Ok(())
+        } else if self.ready.get(Subsystem::Posts) {
+            bail!("Failed to get posts from r/{sub}, try again later.");
+        } else {
+            bail!("Still warming up, try again in a moment!");
+        }
+    }
+
+    /// Add a `QuickPost` to the blacklist and queue it to be persisted. Posts blacklisted in the
+    /// channel for longer than `BLACKLIST_TTL` are forgotten.
+    pub fn add_blacklist(&self, channel: ChannelId, post: QuickPost) {
+        let entry = Blacklisted {
+            key: post.key.clone(),
+            time: Utc::now(),
+        };
+        self.writer.blacklist(channel, post);
+
+        let mut blacklist = self.blacklist.entry(channel).or_default();
+        blacklist.retain(|entry| !entry.is_expired());
+        blacklist.push(entry);
+    }
+
+    /// Fail with a message to try again if the database hasn't been loaded yet, so changes aren't
+    /// made to state that's about to be replaced.
+    pub fn require_database(&self) -> Result<()> {
+        if !self.ready.get(Subsystem::Database) {
+            bail!("Still warming up, try again in a moment!");
+        }
+
+        Ok(())
+    }
+
+    /// Update the bot settings and persist them.
+    pub async fn update_settings(&self, f: impl FnOnce(&mut Settings)) -> Result<Settings> {
+        self.require_database()?;
+        let settings = {
+            let mut settings = self.settings.write().await;
+            f(&mut settings);
+            settings.clone()
+        };
+
+        db::save_settings(&self.db, &settings).await?;
+        Ok(settings)
+    }
+
+    /// Set a channel's last post and queue it to be persisted.
+    pub fn set_last_post(&self, channel: ChannelId, post: QuickPost) {
+        self.writer.last_post(channel, post.clone());
+        self.last_post.insert(channel, post);
+        self.memes_served.fetch_add(1, Ordering::Relaxed);
     }
 
     // /// Reset the blacklist and the blacklist time.
@@ -104,18 +413,20 @@ impl Data {
     // }
 
     /// Add channel info to the database.
+    #[tracing::instrument(skip(self, info), fields(channel = %channel_id))]
     pub async fn add_db_channel(&mut self, channel_id: ChannelId, info: ChannelInfo) -> Result<()> {
-        let channels = self.db.collection::<Channel>("channels");
+        let channels = self.db.collection::<Channel>(db::CHANNELS);
         let channel = channel_id.0.to_string();
         let time = Utc::now().timestamp();
+        let timer = Instant::now();
 
         let doc = channels
             .find_one_and_update(
                 doc! {
-                    "$or": {
-                        "channelID": &channel,
-                        "channelid": &channel,
-                    }
+                    "$or": [
+                        { "channelID": &channel },
+                        { "channelid": &channel },
+                    ]
                 },
                 doc! {
                     "$set": {
@@ -142,29 +453,368 @@ impl Data {
                 .await?;
         }
 
+        metrics::mongo("upsert_channel", timer.elapsed());
         Ok(())
     }
 }
 
-/// Convert reddit posts (submissions) to `QuickPost`s.
+/// Replace the subreddit groups in `SUBS`.
+pub fn set_subs(subs: HashMap<String, Vec<String>>) {
+    SUBS.retain(|group, _| subs.contains_key(group));
+
+    for (group, names) in subs {
+        SUBS.insert(group, names);
+    }
+}
+
+/// Get the names of the subreddits in a group.
+pub fn group(group: &str) -> Option<Vec<String>> {
+    SUBS.get(group).map(|subs| subs.clone())
+}
+
+/// Whether a subreddit is in a group.
+pub fn in_group(group: &str, sub: &str) -> bool {
+    SUBS.get(group)
+        .is_some_and(|subs| subs.iter().any(|s| s.eq_ignore_ascii_case(sub)))
+}
+
+/// Get the names of every subreddit in `SUBS`.
+pub fn all_subs() -> Vec<String> {
+    let mut subs = SUBS
+        .iter()
+        .flat_map(|group| group.value().clone())
+        .collect::<Vec<_>>();
+    subs.sort_unstable();
+    subs.dedup();
+
+    subs
+}
+
+/// Whether a subreddit's refresh time is missing or older than `stale_after`.
+pub fn is_stale(
+    refreshed: &DashMap<String, DateTime<Utc>>,
+    sub: &str,
+    stale_after: Duration,
+) -> bool {
+    refreshed.get(sub).is_none_or(|time| {
+        (Utc::now() - *time)
+            .to_std()
+            .is_ok_and(|age| age > stale_after)
+    })
+}
+
+/// Convert reddit posts (submissions) to `QuickPost`s. Self posts whose text was removed or
+/// deleted are left out, as are text group posts with no text, and news group posts that aren't
+/// links to articles off reddit.
 pub fn submissions_to_quickposts(submissions: Submissions) -> Vec<QuickPost> {
-    submissions
+    let mut posts = submissions
         .data
         .children
         .into_iter()
-        .map(|submission| {
+        .filter_map(|submission| {
             let data = submission.data;
-            // For a link or media post, use the content URL, otherwise use selftext
-            let content = data.url.unwrap_or(data.selftext);
+            if in_group(NEWS_GROUP, &data.subreddit) && (data.is_self || is_reddit(&data.domain)) {
+                return None;
+            }
+            // For a link or media post, use the content URL, otherwise use selftext. Self posts
+            // also have a URL, which links to the post itself
+            let content = match data.url {
+                Some(url) if !data.is_self => url,
+                _ => {
+                    let selftext = unescape(data.selftext.trim());
+                    if selftext == "[removed]"
+                        || selftext == "[deleted]"
+                        || (selftext.is_empty() && in_group(TEXT_GROUP, &data.subreddit))
+                    {
+                        return None;
+                    }
+                    selftext
+                }
+            };
+            let kind = Kind::classify(data.is_self, &data.domain, &content);
 
-            QuickPost {
-                title: data.title,
+            Some(QuickPost {
+                title: unescape(&data.title),
                 score: data.score,
                 content,
                 nsfw: data.over_18,
                 permalink: data.permalink,
                 sub: data.subreddit,
-            }
+                flair: data.link_flair_text.filter(|flair| !flair.is_empty()),
+                // Reddit hides the thumbnails of spoilers
+                spoiler: data.thumbnail == "spoiler",
+                kind,
+                domain: data.domain,
+                created: data.created_utc as i64,
+                key: String::new(),
+            })
         })
-        .collect()
+        .map(|mut post| {
+            post.ensure_key();
+            post
+        })
+        .collect::<Vec<_>>();
+    dedup(&mut posts);
+
+    posts
+}
+
+/// Remove duplicates of posts, keeping the first of each.
+pub fn dedup(posts: &mut Vec<QuickPost>) {
+    let mut seen = HashSet::new();
+    posts.retain(|post| seen.insert(post.key.clone()));
+}
+
+/// Get the ID of a reddit post from a link to it or its permalink.
+fn reddit_post_id(link: &str) -> Option<&str> {
+    let path = match link.split_once("://") {
+        Some((_, rest)) => {
+            let (host, path) = rest.split_once('/')?;
+            if !is_reddit(host) {
+                return None;
+            }
+            path
+        }
+        None => link,
+    };
+
+    let mut segments = path.split('/');
+    segments.find(|segment| *segment == "comments")?;
+    segments.next().filter(|id| !id.is_empty())
+}
+
+/// The lowercase file extension of a URL's path, or an empty string if it has none.
+fn extension(url: &str) -> String {
+    let path = url.split(['?', '#']).next().unwrap_or(url);
+    let file = path.rsplit('/').next().unwrap_or(path);
+
+    file.rsplit_once('.')
+        .map_or_else(String::new, |(_, ext)| ext.to_lowercase())
+}
+
+/// Normalize a URL so that links to the same thing are equal. The scheme, `www.` and `m.`
+/// subdomains, trailing slashes, fragments and tracking parameters are ignored. Media hosts use
+/// query parameters for resizing, so they're ignored entirely for links to media files, but not
+/// for pages like `youtube.com/watch?v=...`.
+fn normalize_url(link: &str, kind: Kind) -> Option<String> {
+    let url = Url::parse(link).ok()?;
+    let host = url
+        .host_str()?
+        .trim_start_matches("www.")
+        .trim_start_matches("m.");
+    // Reddit's previews are resized copies of its images
+    let host = if host == "preview.redd.it" {
+        "i.redd.it"
+    } else {
+        host
+    };
+
+    let mut normalized = format!("url:{host}{}", url.path().trim_end_matches('/'));
+    if matches!(kind, Kind::Link | Kind::Text) || extension(link).is_empty() {
+        let mut query = url
+            .query_pairs()
+            .filter(|(key, _)| !key.starts_with("utm_"))
+            .map(|(key, value)| format!("{key}={value}"))
+            .collect::<Vec<_>>();
+        query.sort_unstable();
+        if !query.is_empty() {
+            normalized.push('?');
+            normalized.push_str(&query.join("&"));
+        }
+    }
+
+    Some(normalized)
+}
+
+/// Whether a domain is reddit's own, e.g. for crossposts and hosted media.
+fn is_reddit(domain: &str) -> bool {
+    ["reddit.com", "redd.it"]
+        .iter()
+        .any(|reddit| domain == *reddit || domain.ends_with(&format!(".{reddit}")))
+}
+
+/// Decode the HTML entities reddit escapes in titles and self-text.
+fn unescape(s: &str) -> String {
+    s.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&amp;", "&")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn post(kind: Kind, content: &str) -> QuickPost {
+        QuickPost {
+            title: "title".to_string(),
+            score: 1.0,
+            content: content.to_string(),
+            nsfw: false,
+            permalink: "/r/memes/comments/abc123/title/".to_string(),
+            sub: "memes".to_string(),
+            flair: None,
+            spoiler: false,
+            kind,
+            domain: String::new(),
+            created: 0,
+            key: String::new(),
+        }
+    }
+
+    #[test]
+    fn classifies_by_extension() {
+        let classify = |url| Kind::classify(false, "example.com", url);
+
+        assert_eq!(classify("https://example.com/a.JPG"), Kind::Image);
+        assert_eq!(
+            classify("https://example.com/a.webp?width=640"),
+            Kind::Image
+        );
+        assert_eq!(classify("https://example.com/a.gif"), Kind::Gif);
+        assert_eq!(classify("https://example.com/a.gifv#loop"), Kind::Gif);
+        assert_eq!(classify("https://example.com/a.mp4"), Kind::Video);
+        assert_eq!(classify("https://example.com/article.html"), Kind::Link);
+        assert_eq!(classify("https://example.com/"), Kind::Link);
+    }
+
+    #[test]
+    fn classifies_by_domain() {
+        assert_eq!(
+            Kind::classify(false, "i.redd.it", "https://i.redd.it/abc"),
+            Kind::Image
+        );
+        assert_eq!(
+            Kind::classify(false, "v.redd.it", "https://v.redd.it/abc"),
+            Kind::Video
+        );
+        assert_eq!(
+            Kind::classify(
+                false,
+                "www.youtube.com",
+                "https://www.youtube.com/watch?v=abc"
+            ),
+            Kind::Video
+        );
+        assert_eq!(
+            Kind::classify(false, "redgifs.com", "https://redgifs.com/watch/abc"),
+            Kind::Gif
+        );
+        // Other hosts are links unless the URL has a media extension
+        assert_eq!(
+            Kind::classify(false, "imgur.com", "https://imgur.com/gallery"),
+            Kind::Link
+        );
+    }
+
+    #[test]
+    fn classifies_self_posts_as_text() {
+        assert_eq!(
+            Kind::classify(
+                true,
+                "self.memes",
+                "https://www.reddit.com/r/memes/comments/abc/a.png"
+            ),
+            Kind::Text
+        );
+    }
+
+    #[test]
+    fn embeds_images_and_gif_files() {
+        let image = post(Kind::Image, "https://i.redd.it/abc.png");
+        assert_eq!(image.image(), Some("https://i.redd.it/abc.png"));
+        assert!(post(Kind::Gif, "https://i.redd.it/abc.gif")
+            .image()
+            .is_some());
+
+        assert!(post(Kind::Gif, "https://i.imgur.com/abc.gifv")
+            .image()
+            .is_none());
+        assert!(post(Kind::Image, "http://example.com/abc.png")
+            .image()
+            .is_none());
+        assert!(post(Kind::Link, "https://example.com/abc.png")
+            .image()
+            .is_none());
+        assert!(post(Kind::Text, "https://example.com/abc.png")
+            .image()
+            .is_none());
+    }
+
+    #[test]
+    fn normalizes_urls() {
+        assert_eq!(
+            normalize_url(
+                "https://www.Example.com/a/?utm_source=x&b=2&a=1#top",
+                Kind::Link
+            ),
+            Some("url:example.com/a?a=1&b=2".to_string())
+        );
+        assert_eq!(
+            normalize_url("http://example.com/a", Kind::Link),
+            normalize_url("https://m.example.com/a/", Kind::Link)
+        );
+        assert_eq!(normalize_url("not a url", Kind::Link), None);
+    }
+
+    #[test]
+    fn ignores_resizing_of_media_files() {
+        assert_eq!(
+            normalize_url(
+                "https://preview.redd.it/abc.png?width=640&format=png&auto=webp",
+                Kind::Image
+            ),
+            Some("url:i.redd.it/abc.png".to_string())
+        );
+        // Different videos on the same page
+        assert_ne!(
+            normalize_url("https://www.youtube.com/watch?v=abc", Kind::Video),
+            normalize_url("https://m.youtube.com/watch?v=xyz", Kind::Video)
+        );
+        assert_eq!(
+            normalize_url(
+                "https://www.youtube.com/watch?v=abc&utm_medium=share",
+                Kind::Video
+            ),
+            normalize_url("https://m.youtube.com/watch?v=abc", Kind::Video)
+        );
+    }
+
+    #[test]
+    fn keys_crossposts_by_parent_post() {
+        let crosspost = post(
+            Kind::Link,
+            "https://www.reddit.com/r/funny/comments/xyz789/original/",
+        );
+        assert_eq!(crosspost.canonical_key(), "reddit:xyz789");
+        // Reddit media isn't a post
+        assert_eq!(
+            post(Kind::Video, "https://v.redd.it/xyz789").canonical_key(),
+            "url:v.redd.it/xyz789"
+        );
+    }
+
+    #[test]
+    fn keys_media_by_url_and_self_posts_by_id() {
+        assert_eq!(
+            post(Kind::Image, "https://preview.redd.it/abc.png?width=640").canonical_key(),
+            post(Kind::Image, "https://i.redd.it/abc.png").canonical_key()
+        );
+        assert_eq!(
+            post(Kind::Text, "https://example.com/a").canonical_key(),
+            "reddit:abc123"
+        );
+        // Unparseable links fall back to the post
+        assert_eq!(post(Kind::Link, "").canonical_key(), "reddit:abc123");
+    }
+
+    #[test]
+    fn ensures_missing_keys_only() {
+        let mut keyed = post(Kind::Image, "https://i.redd.it/abc.png");
+        keyed.ensure_key();
+        assert_eq!(keyed.key, "url:i.redd.it/abc.png");
+
+        keyed.key = "custom".to_string();
+        keyed.ensure_key();
+        assert_eq!(keyed.key, "custom");
+    }
 }
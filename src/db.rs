@@ -1,21 +1,73 @@
 //! Mongo stuff.
 
+use std::collections::HashMap;
 use std::env;
-use std::sync::Arc;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
 
-use anyhow::{Context, Result};
+use anyhow::{anyhow, Context, Result};
+use chrono::{DateTime, Utc};
+use dashmap::mapref::entry::Entry;
 use dashmap::DashMap;
-use mongodb::bson::{self, Bson};
-use mongodb::options::ClientOptions;
-use mongodb::{Client, Database};
-use poise::futures_util::{future, Stream, StreamExt};
-use poise::serenity_prelude::ChannelId;
-use tracing::error;
+use mongodb::bson::serde_helpers::chrono_datetime_as_bson_datetime;
+use mongodb::bson::{self, doc, Bson, Document};
+use mongodb::error::ErrorKind;
+use mongodb::options::{ClientOptions, IndexOptions, ReplaceOptions, Tls, TlsOptions};
+use mongodb::{Client, Database, IndexModel};
+use poise::futures_util::TryStreamExt;
+use poise::serenity_prelude::{ChannelId, GuildId};
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinHandle;
+use tracing::{error, info, info_span, warn, Instrument};
+
+use crate::data::{Blacklisted, Kind, QuickPost};
+use crate::filter::Filter;
+use crate::{metrics, setup};
+
+/// Collection of channels the bot is active in.
+pub const CHANNELS: &str = "channels";
+/// Collection of subreddits banned in channels.
+pub const BANS: &str = "bans";
+/// Collection of the last post sent to each channel.
+pub const HISTORY: &str = "history";
+/// Collection of posts blacklisted in channels.
+pub const BLACKLIST: &str = "blacklist";
+/// Collection of scheduled posts to channels.
+pub const AUTOPOSTS: &str = "autoposts";
+/// Collection of daily digests of top posts sent to channels.
+pub const DIGESTS: &str = "digests";
+/// Collection of the kind of post each channel is limited to.
+pub const MEDIA: &str = "media";
+/// Collection of guilds' post filters.
+pub const FILTERS: &str = "filters";
+/// Collection of perceptual hashes of post images, with the `image-hash` feature.
+pub const HASHES: &str = "hashes";
+/// Collection of bot-wide settings changed at runtime.
+pub const SETTINGS: &str = "settings";
+
+/// ID of the bot settings document.
+const SETTINGS_ID: &str = "bot";
+
+/// How long a channel's post history is kept.
+pub const HISTORY_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+/// How long a post stays blacklisted in a channel.
+pub const BLACKLIST_TTL: Duration = Duration::from_secs(3 * 60 * 60);
+/// How long an image's perceptual hash is kept after it was computed.
+pub const HASH_TTL: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// Default interval between database health checks.
+const HEALTH_INTERVAL: Duration = Duration::from_secs(30);
+/// Ping round trip time above which the database is considered degraded.
+const HEALTH_SLOW: Duration = Duration::from_secs(1);
+/// Interval between flushes of pending writes.
+const WRITE_INTERVAL: Duration = Duration::from_secs(5);
+/// Number of pending writes that triggers a flush before the interval elapses.
+const WRITE_BATCH_SIZE: usize = 100;
 
 /// Discord channel data for mongo.
 #[derive(Debug, serde::Serialize, serde::Deserialize)]
 pub struct Channel {
-    #[serde(rename = "channel", with = "crate::serde::channel_id")]
+    #[serde(rename = "channelID", with = "crate::serde::channel_id")]
     pub channel_id: ChannelId,
     #[serde(flatten)]
     pub info: ChannelInfo,
@@ -37,17 +89,331 @@ pub struct BannedSub {
     pub subreddit: String,
 }
 
-/// Create a mongodb client.
+/// A post sent to a discord channel.
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct ChannelPost {
+    #[serde(rename = "channelID", with = "crate::serde::channel_id")]
+    pub channel_id: ChannelId,
+    pub post: QuickPost,
+    #[serde(with = "chrono_datetime_as_bson_datetime")]
+    pub time: DateTime<Utc>,
+}
+
+/// A subreddit group posted to a discord channel on a schedule.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct Autopost {
+    #[serde(rename = "channelID", with = "crate::serde::channel_id")]
+    pub channel_id: ChannelId,
+    pub group: String,
+    /// `every <duration>` or a cron expression.
+    pub schedule: String,
+    /// The next time to post.
+    #[serde(with = "chrono_datetime_as_bson_datetime")]
+    pub next: DateTime<Utc>,
+}
+
+/// A daily digest of a subreddit group's top posts sent to a discord channel.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct Digest {
+    #[serde(rename = "channelID", with = "crate::serde::channel_id")]
+    pub channel_id: ChannelId,
+    pub group: String,
+    /// Local time of day to send the digest at, e.g. `09:00`.
+    pub time: String,
+    /// IANA timezone of `time`, e.g. `America/New_York`.
+    pub timezone: String,
+    /// Number of posts in each digest.
+    pub count: u32,
+    /// The next time to send the digest.
+    #[serde(with = "chrono_datetime_as_bson_datetime")]
+    pub next: DateTime<Utc>,
+    /// Keys of posts sent in recent digests, so they and their duplicates aren't repeated.
+    #[serde(default)]
+    pub sent: Vec<String>,
+}
+
+/// The kind of post a discord channel is limited to.
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct ChannelMedia {
+    #[serde(rename = "channelID", with = "crate::serde::channel_id")]
+    pub channel_id: ChannelId,
+    pub kind: Kind,
+}
+
+/// A discord guild's post filters.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct Filters {
+    #[serde(rename = "guildID", with = "crate::serde::guild_id")]
+    pub guild_id: GuildId,
+    /// Minimum score of posts.
+    #[serde(default)]
+    pub min_score: Option<f64>,
+    /// Keywords that block posts with them in the title, case insensitive.
+    #[serde(default)]
+    pub blocked_keywords: Vec<String>,
+    /// Regexes that block posts with a matching title, case insensitive.
+    #[serde(default)]
+    pub blocked_patterns: Vec<String>,
+    /// Link flairs that block posts, case insensitive.
+    #[serde(default)]
+    pub blocked_flairs: Vec<String>,
+    /// Link flairs that posts must have one of, if any, case insensitive.
+    #[serde(default)]
+    pub required_flairs: Vec<String>,
+    /// Whether posts marked as spoilers are blocked.
+    #[serde(default)]
+    pub block_spoilers: bool,
+    /// News domains that posts must link to one of, if any, including subdomains.
+    #[serde(default)]
+    pub allowed_domains: Vec<String>,
+    /// News domains that block posts linking to them, including subdomains.
+    #[serde(default)]
+    pub blocked_domains: Vec<String>,
+}
+
+impl Filters {
+    /// Filters that allow every post.
+    pub const fn new(guild_id: GuildId) -> Self {
+        Self {
+            guild_id,
+            min_score: None,
+            blocked_keywords: Vec::new(),
+            blocked_patterns: Vec::new(),
+            blocked_flairs: Vec::new(),
+            required_flairs: Vec::new(),
+            block_spoilers: false,
+            allowed_domains: Vec::new(),
+            blocked_domains: Vec::new(),
+        }
+    }
+}
+
+/// The perceptual hash of a post's image.
+#[cfg(feature = "image-hash")]
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct ImageHash {
+    /// The post's key.
+    pub key: String,
+    /// The hash's bits, since BSON has no unsigned integers.
+    pub hash: i64,
+    /// When the hash was computed.
+    #[serde(with = "chrono_datetime_as_bson_datetime")]
+    pub time: DateTime<Utc>,
+}
+
+/// Bot-wide settings changed at runtime by bot owners.
+#[derive(Debug, Default, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct Settings {
+    /// Whether commands from anyone other than bot owners are rejected.
+    pub maintenance: bool,
+    /// Message replied to rejected commands during maintenance.
+    pub maintenance_message: Option<String>,
+    /// Subreddit groups that are disabled everywhere.
+    pub disabled_groups: Vec<String>,
+}
+
+impl Settings {
+    /// Message replied to rejected commands during maintenance.
+    pub fn maintenance_message(&self) -> &str {
+        self.maintenance_message
+            .as_deref()
+            .unwrap_or("The bot is down for maintenance, try again later!")
+    }
+
+    /// Whether a subreddit group is disabled everywhere.
+    pub fn is_disabled(&self, group: &str) -> bool {
+        self.disabled_groups
+            .iter()
+            .any(|disabled| disabled == group)
+    }
+}
+
+/// A pending write to the database.
+#[derive(Debug)]
+enum Write {
+    Blacklist(ChannelPost),
+    LastPost(ChannelPost),
+    /// Flush pending writes now, and notify the sender when done.
+    Flush(oneshot::Sender<()>),
+}
+
+/// Handle for queueing blacklist and post history writes, which are batched and flushed to the
+/// database in the background.
+#[derive(Debug, Clone)]
+pub struct Writer {
+    tx: mpsc::UnboundedSender<Write>,
+}
+
+impl Writer {
+    /// Spawn the background task that flushes pending writes. The task flushes any remaining
+    /// writes and exits when every `Writer` has been dropped.
+    pub fn spawn(db: Database) -> (Self, JoinHandle<()>) {
+        let (tx, rx) = mpsc::unbounded_channel();
+
+        (Self { tx }, tokio::spawn(write_behind(db, rx)))
+    }
+
+    /// Queue a post to be added to a channel's blacklist.
+    #[tracing::instrument(skip(self, post), fields(channel = %channel_id))]
+    pub fn blacklist(&self, channel_id: ChannelId, post: QuickPost) {
+        self.send(Write::Blacklist(ChannelPost {
+            channel_id,
+            post,
+            time: Utc::now(),
+        }));
+    }
+
+    /// Queue a post to be stored as a channel's last post.
+    #[tracing::instrument(skip(self, post), fields(channel = %channel_id))]
+    pub fn last_post(&self, channel_id: ChannelId, post: QuickPost) {
+        self.send(Write::LastPost(ChannelPost {
+            channel_id,
+            post,
+            time: Utc::now(),
+        }));
+    }
+
+    /// Flush pending writes, waiting until they're written.
+    pub async fn flush(&self) {
+        let (tx, rx) = oneshot::channel();
+
+        self.send(Write::Flush(tx));
+        let _ = rx.await;
+    }
+
+    fn send(&self, write: Write) {
+        if self.tx.send(write).is_err() {
+            error!("failed to queue database write, the writer has stopped");
+        }
+    }
+}
+
+/// Collect pending writes and flush them in batches.
+async fn write_behind(db: Database, mut rx: mpsc::UnboundedReceiver<Write>) {
+    let mut ticker = tokio::time::interval(WRITE_INTERVAL);
+    let mut blacklist = Vec::new();
+    // Only the newest post per channel needs to be written
+    let mut history = HashMap::new();
+
+    loop {
+        tokio::select! {
+            write = rx.recv() => match write {
+                Some(Write::Blacklist(entry)) => blacklist.push(entry),
+                Some(Write::LastPost(entry)) => {
+                    history.insert(entry.channel_id, entry);
+                }
+                Some(Write::Flush(done)) => {
+                    flush(&db, &mut blacklist, &mut history).await;
+                    let _ = done.send(());
+                    continue;
+                }
+                None => break,
+            },
+            _ = ticker.tick() => {
+                flush(&db, &mut blacklist, &mut history).await;
+                continue;
+            }
+        }
+
+        if blacklist.len() + history.len() >= WRITE_BATCH_SIZE {
+            flush(&db, &mut blacklist, &mut history).await;
+        }
+    }
+
+    flush(&db, &mut blacklist, &mut history).await;
+}
+
+/// Write pending blacklist entries and last posts to the database.
+#[tracing::instrument(skip_all, fields(blacklist = blacklist.len(), history = history.len()))]
+async fn flush(
+    db: &Database,
+    blacklist: &mut Vec<ChannelPost>,
+    history: &mut HashMap<ChannelId, ChannelPost>,
+) {
+    if !blacklist.is_empty() {
+        let timer = Instant::now();
+        let result = db
+            .collection::<ChannelPost>(BLACKLIST)
+            .insert_many(blacklist.iter(), None)
+            .await;
+        metrics::mongo("insert_blacklist", timer.elapsed());
+
+        match result {
+            Ok(_) => blacklist.clear(),
+            Err(e) => {
+                error!("failed to write blacklist entries, retrying in the next flush: {e}");
+                // Keep the newest entries so a database outage doesn't grow the queue forever
+                let dropped = blacklist.len().saturating_sub(WRITE_BATCH_SIZE);
+                if dropped > 0 {
+                    blacklist.drain(..dropped);
+                    warn!("dropped {dropped} blacklist entries");
+                    metrics::dropped_writes(dropped);
+                }
+            }
+        }
+    }
+
+    let collection = db.collection::<ChannelPost>(HISTORY);
+    let options = ReplaceOptions::builder().upsert(true).build();
+    let mut failed = Vec::new();
+
+    for (channel_id, entry) in history.drain() {
+        let timer = Instant::now();
+        let result = collection
+            .replace_one(
+                doc! { "channelID": channel_id.0.to_string() },
+                &entry,
+                options.clone(),
+            )
+            .await;
+        metrics::mongo("replace_history", timer.elapsed());
+
+        if let Err(e) = result {
+            error!("failed to write last post, retrying in the next flush: {e}");
+            failed.push((channel_id, entry));
+        }
+    }
+
+    // Only one entry per channel is kept, so failed history writes can't pile up
+    history.extend(failed);
+}
+
+/// Create a mongodb client. The client connects lazily, use `ping` to check that the database is
+/// reachable.
 #[tracing::instrument]
 pub async fn client_and_db() -> Result<(Client, Database)> {
     let mongo_uri =
         env::var("MEMER_MONGO_URI").context("missing MEMER_MONGO_URI environment variable")?;
-    let mut client_options = ClientOptions::parse(mongo_uri).await?;
+    let mut client_options = ClientOptions::parse(mongo_uri)
+        .await
+        .context("invalid MEMER_MONGO_URI environment variable")?;
     let db = env::var("MEMER_MONGO_DB").context("missing MEMER_MONGO_DB environment variable")?;
 
     client_options.default_database = Some(db);
-    // TODO: client_options.tls?
-    // TODO: client_options.max_idle_time?
+    client_options.app_name =
+        Some(env::var("MEMER_MONGO_APP_NAME").unwrap_or_else(|_| "memer".to_string()));
+
+    let ca_file = setup::env_parse::<PathBuf>("MEMER_MONGO_TLS_CA_FILE")?;
+    let cert_key_file = setup::env_parse::<PathBuf>("MEMER_MONGO_TLS_CERT_KEY_FILE")?;
+    if ca_file.is_some() || cert_key_file.is_some() {
+        client_options.tls = Some(Tls::Enabled(
+            TlsOptions::builder()
+                .ca_file_path(ca_file)
+                .cert_key_file_path(cert_key_file)
+                .build(),
+        ));
+    }
+
+    if let Some(size) = setup::env_parse("MEMER_MONGO_MAX_POOL_SIZE")? {
+        client_options.max_pool_size = Some(size);
+    }
+    if let Some(time) = setup::env_duration("MEMER_MONGO_MAX_IDLE_TIME")? {
+        client_options.max_idle_time = Some(time);
+    }
+    if let Some(timeout) = setup::env_duration("MEMER_MONGO_SERVER_SELECTION_TIMEOUT")? {
+        client_options.server_selection_timeout = Some(timeout);
+    }
 
     let client = mongodb::Client::with_options(client_options)?;
     let db = client
@@ -57,32 +423,591 @@ pub async fn client_and_db() -> Result<(Client, Database)> {
     Ok((client, db))
 }
 
-/// Get all active channels' info from the database.
+/// Check that the database is reachable.
 #[tracing::instrument(skip_all)]
-pub async fn all_channels(db: &Database) -> Result<Arc<DashMap<ChannelId, ChannelInfo>>> {
-    // TODO: explicitly type collection if there are no errors deserializing
-    let cursor = db.collection("channels").find(None, None).await?;
-    let (lo, hi) = cursor.size_hint();
-    let channels = Arc::new(DashMap::with_capacity(hi.unwrap_or(lo)));
-
-    let handles = cursor
-        .map(|res| {
-            let channels = channels.clone();
-
-            tokio::spawn(async move {
-                if let Ok(doc) = res {
-                    match bson::from_bson::<Channel>(Bson::Document(doc)) {
-                        Ok(channel) => {
-                            channels.insert(channel.channel_id, channel.info);
+pub async fn ping(db: &Database) -> Result<()> {
+    db.run_command(doc! { "ping": 1 }, None)
+        .await
+        .context("failed to connect to the database, check MEMER_MONGO_URI")?;
+    info!("connected to database: {}", db.name());
+
+    Ok(())
+}
+
+/// Get the interval between database health checks.
+pub fn health_interval() -> Result<Duration> {
+    Ok(setup::env_duration("MEMER_MONGO_HEALTH_INTERVAL")?.unwrap_or(HEALTH_INTERVAL))
+}
+
+/// Periodically ping the database, logging when it becomes degraded and when it recovers.
+pub async fn health_probe(db: Database, interval: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+    let mut healthy = true;
+
+    // The first tick completes immediately, and the database was just checked on startup
+    ticker.tick().await;
+
+    loop {
+        ticker.tick().await;
+
+        let timer = Instant::now();
+        let res = db
+            .run_command(doc! { "ping": 1 }, None)
+            .instrument(info_span!("health_probe"))
+            .await;
+        let elapsed = timer.elapsed();
+        metrics::mongo("ping", elapsed);
+
+        match res {
+            Ok(_) if elapsed > HEALTH_SLOW => {
+                warn!(
+                    "database is degraded: ping took {}",
+                    humantime::format_duration(elapsed)
+                );
+                healthy = false;
+            }
+            Ok(_) => {
+                if !healthy {
+                    info!("database recovered");
+                    healthy = true;
+                }
+            }
+            Err(e) => {
+                warn!("database is unreachable: {e}");
+                healthy = false;
+            }
+        }
+    }
+}
+
+/// Create the indexes used by the bot's queries, if they don't already exist.
+#[tracing::instrument(skip_all)]
+pub async fn ensure_indexes(db: &Database) -> Result<()> {
+    migrate_channels(db).await?;
+    migrate_hashes(db).await?;
+    create_indexes(
+        db,
+        CHANNELS,
+        vec![index("channel_unique", doc! { "channelID": 1 }, |opts| {
+            opts.unique = Some(true);
+            // Documents that couldn't be migrated would otherwise all be indexed as null
+            opts.partial_filter_expression = Some(doc! { "channelID": { "$exists": true } });
+        })],
+    )
+    .await?;
+    create_indexes(
+        db,
+        BANS,
+        vec![index(
+            "channel_subreddit_unique",
+            doc! { "channelID": 1, "subreddit": 1 },
+            |opts| opts.unique = Some(true),
+        )],
+    )
+    .await?;
+    create_indexes(
+        db,
+        AUTOPOSTS,
+        vec![index(
+            "channel_group_unique",
+            doc! { "channelID": 1, "group": 1 },
+            |opts| opts.unique = Some(true),
+        )],
+    )
+    .await?;
+    create_indexes(
+        db,
+        DIGESTS,
+        vec![index(
+            "channel_group_unique",
+            doc! { "channelID": 1, "group": 1 },
+            |opts| opts.unique = Some(true),
+        )],
+    )
+    .await?;
+    create_indexes(
+        db,
+        MEDIA,
+        vec![index("channel_unique", doc! { "channelID": 1 }, |opts| {
+            opts.unique = Some(true);
+        })],
+    )
+    .await?;
+    create_indexes(
+        db,
+        HASHES,
+        vec![
+            index("key_unique", doc! { "key": 1 }, |opts| {
+                opts.unique = Some(true);
+            }),
+            index("time_ttl", doc! { "time": 1 }, |opts| {
+                opts.expire_after = Some(HASH_TTL);
+            }),
+        ],
+    )
+    .await?;
+    create_indexes(
+        db,
+        FILTERS,
+        vec![index("guild_unique", doc! { "guildID": 1 }, |opts| {
+            opts.unique = Some(true);
+        })],
+    )
+    .await?;
+    create_indexes(
+        db,
+        HISTORY,
+        vec![
+            index("channel_unique", doc! { "channelID": 1 }, |opts| {
+                opts.unique = Some(true);
+            }),
+            index("time_ttl", doc! { "time": 1 }, |opts| {
+                opts.expire_after = Some(HISTORY_TTL);
+            }),
+        ],
+    )
+    .await?;
+    create_indexes(
+        db,
+        BLACKLIST,
+        vec![
+            index("channel", doc! { "channelID": 1 }, |_| ()),
+            index("time_ttl", doc! { "time": 1 }, |opts| {
+                opts.expire_after = Some(BLACKLIST_TTL);
+            }),
+        ],
+    )
+    .await?;
+
+    info!("database indexes are up to date");
+    Ok(())
+}
+
+/// Move channel IDs stored under the legacy `channel` and `channelid` fields to `channelID`.
+#[tracing::instrument(skip_all)]
+async fn migrate_channels(db: &Database) -> Result<()> {
+    let res = db
+        .collection::<Document>(CHANNELS)
+        .update_many(
+            doc! {
+                "$or": [
+                    { "channel": { "$exists": true } },
+                    { "channelid": { "$exists": true } },
+                ]
+            },
+            vec![
+                doc! {
+                    "$set": {
+                        "channelID": {
+                            "$ifNull": ["$channelID", { "$ifNull": ["$channel", "$channelid"] }]
                         }
-                        Err(e) => error!("failed to deserialize channel from bson: {e}"),
                     }
+                },
+                doc! { "$unset": ["channel", "channelid"] },
+            ],
+            None,
+        )
+        .await
+        .context("failed to migrate legacy channel documents")?;
+
+    if res.modified_count > 0 {
+        info!("migrated {} legacy channel documents", res.modified_count);
+    }
+
+    Ok(())
+}
+
+/// Set the time of image hashes saved before hashes expired, so they expire too.
+#[tracing::instrument(skip_all)]
+async fn migrate_hashes(db: &Database) -> Result<()> {
+    let res = db
+        .collection::<Document>(HASHES)
+        .update_many(
+            doc! { "time": { "$exists": false } },
+            doc! { "$currentDate": { "time": true } },
+            None,
+        )
+        .await
+        .context("failed to migrate legacy image hashes")?;
+
+    if res.modified_count > 0 {
+        info!("migrated {} legacy image hashes", res.modified_count);
+    }
+
+    Ok(())
+}
+
+/// Build a named index model.
+fn index(name: &str, keys: Document, f: impl FnOnce(&mut IndexOptions)) -> IndexModel {
+    let mut options = IndexOptions::default();
+    options.name = Some(name.to_string());
+    f(&mut options);
+
+    IndexModel::builder().keys(keys).options(options).build()
+}
+
+/// Create indexes on a collection, explaining conflicts with indexes that already exist.
+async fn create_indexes(db: &Database, collection: &str, indexes: Vec<IndexModel>) -> Result<()> {
+    match db
+        .collection::<Document>(collection)
+        .create_indexes(indexes, None)
+        .await
+    {
+        Ok(_) => Ok(()),
+        // IndexOptionsConflict | IndexKeySpecsConflict
+        Err(e) => match *e.kind {
+            ErrorKind::Command(ref err) if matches!(err.code, 85 | 86) => Err(anyhow!(
+                "an existing index on the `{collection}` collection conflicts with the bot's \
+                 indexes, drop it and restart: {}",
+                err.message
+            )),
+            // DuplicateKey
+            ErrorKind::Command(ref err) if err.code == 11000 => Err(anyhow!(
+                "the `{collection}` collection has duplicate documents that a unique index \
+                 doesn't allow, remove the duplicates and restart: {}",
+                err.message
+            )),
+            _ => Err(e).with_context(|| format!("failed to create indexes on `{collection}`")),
+        },
+    }
+}
+
+/// Load all blacklisted posts that are still within the blacklist window. Posts that are already
+/// blacklisted are skipped.
+#[tracing::instrument(skip_all)]
+pub async fn blacklist(
+    db: &Database,
+    blacklist: &DashMap<ChannelId, Vec<Blacklisted>>,
+) -> Result<()> {
+    let mut cursor = db
+        .collection::<ChannelPost>(BLACKLIST)
+        .find(doc! { "time": { "$gte": since(BLACKLIST_TTL) } }, None)
+        .await?;
+
+    while let Some(mut entry) = cursor.try_next().await? {
+        entry.post.ensure_key();
+        let post = Blacklisted {
+            key: entry.post.key,
+            time: entry.time,
+        };
+        match blacklist.entry(entry.channel_id) {
+            Entry::Occupied(mut posts) => {
+                if !posts
+                    .get()
+                    .iter()
+                    .any(|blacklisted| blacklisted.key == post.key)
+                {
+                    posts.get_mut().push(post);
                 }
-            })
-        })
-        .collect::<Vec<_>>()
-        .await;
-    future::join_all(handles).await;
+            }
+            Entry::Vacant(posts) => {
+                posts.insert(vec![post]);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Load the last post sent to each channel. Posts sent since startup are kept.
+#[tracing::instrument(skip_all)]
+pub async fn last_posts(db: &Database, last_posts: &DashMap<ChannelId, QuickPost>) -> Result<()> {
+    let mut cursor = db
+        .collection::<ChannelPost>(HISTORY)
+        .find(doc! { "time": { "$gte": since(HISTORY_TTL) } }, None)
+        .await?;
+
+    while let Some(mut entry) = cursor.try_next().await? {
+        entry.post.ensure_key();
+        last_posts.entry(entry.channel_id).or_insert(entry.post);
+    }
+
+    Ok(())
+}
+
+/// The BSON datetime `ttl` ago. Documents are only removed by a TTL index periodically, so queries
+/// also need to filter on time.
+fn since(ttl: Duration) -> bson::DateTime {
+    bson::DateTime::from_millis(
+        bson::DateTime::now().timestamp_millis() - i64::try_from(ttl.as_millis()).unwrap_or(0),
+    )
+}
+
+/// Load the subreddits banned in each channel.
+#[tracing::instrument(skip_all)]
+pub async fn bans(db: &Database, bans: &DashMap<ChannelId, Vec<String>>) -> Result<()> {
+    let mut cursor = db.collection::<BannedSub>(BANS).find(None, None).await?;
+
+    while let Some(ban) = cursor.try_next().await? {
+        let mut subs = bans.entry(ban.channel_id).or_default();
+        let sub = ban.subreddit.to_lowercase();
+        if !subs.contains(&sub) {
+            subs.push(sub);
+        }
+    }
+
+    Ok(())
+}
+
+/// Load all scheduled posts. Schedules created since startup are kept.
+#[tracing::instrument(skip_all)]
+pub async fn autoposts(
+    db: &Database,
+    autoposts: &DashMap<(ChannelId, String), Autopost>,
+) -> Result<()> {
+    let mut cursor = db
+        .collection::<Autopost>(AUTOPOSTS)
+        .find(None, None)
+        .await?;
+
+    while let Some(autopost) = cursor.try_next().await? {
+        autoposts
+            .entry((autopost.channel_id, autopost.group.clone()))
+            .or_insert(autopost);
+    }
+
+    Ok(())
+}
+
+/// Create or replace a scheduled post.
+#[tracing::instrument(skip_all, fields(channel = %autopost.channel_id, group = %autopost.group))]
+pub async fn save_autopost(db: &Database, autopost: &Autopost) -> Result<()> {
+    let timer = Instant::now();
+    db.collection::<Autopost>(AUTOPOSTS)
+        .replace_one(
+            doc! {
+                "channelID": autopost.channel_id.0.to_string(),
+                "group": &autopost.group,
+            },
+            autopost,
+            ReplaceOptions::builder().upsert(true).build(),
+        )
+        .await?;
+    metrics::mongo("replace_autopost", timer.elapsed());
+
+    Ok(())
+}
+
+/// Delete a scheduled post.
+#[tracing::instrument(skip(db), fields(channel = %channel_id))]
+pub async fn delete_autopost(db: &Database, channel_id: ChannelId, group: &str) -> Result<()> {
+    let timer = Instant::now();
+    db.collection::<Autopost>(AUTOPOSTS)
+        .delete_one(
+            doc! { "channelID": channel_id.0.to_string(), "group": group },
+            None,
+        )
+        .await?;
+    metrics::mongo("delete_autopost", timer.elapsed());
+
+    Ok(())
+}
+
+/// Load all daily digests. Digests created since startup are kept.
+#[tracing::instrument(skip_all)]
+pub async fn digests(db: &Database, digests: &DashMap<(ChannelId, String), Digest>) -> Result<()> {
+    let mut cursor = db.collection::<Digest>(DIGESTS).find(None, None).await?;
+
+    while let Some(digest) = cursor.try_next().await? {
+        digests
+            .entry((digest.channel_id, digest.group.clone()))
+            .or_insert(digest);
+    }
+
+    Ok(())
+}
+
+/// Create or replace a daily digest.
+#[tracing::instrument(skip_all, fields(channel = %digest.channel_id, group = %digest.group))]
+pub async fn save_digest(db: &Database, digest: &Digest) -> Result<()> {
+    let timer = Instant::now();
+    db.collection::<Digest>(DIGESTS)
+        .replace_one(
+            doc! {
+                "channelID": digest.channel_id.0.to_string(),
+                "group": &digest.group,
+            },
+            digest,
+            ReplaceOptions::builder().upsert(true).build(),
+        )
+        .await?;
+    metrics::mongo("replace_digest", timer.elapsed());
+
+    Ok(())
+}
+
+/// Delete a daily digest.
+#[tracing::instrument(skip(db), fields(channel = %channel_id))]
+pub async fn delete_digest(db: &Database, channel_id: ChannelId, group: &str) -> Result<()> {
+    let timer = Instant::now();
+    db.collection::<Digest>(DIGESTS)
+        .delete_one(
+            doc! { "channelID": channel_id.0.to_string(), "group": group },
+            None,
+        )
+        .await?;
+    metrics::mongo("delete_digest", timer.elapsed());
+
+    Ok(())
+}
+
+/// Load the kind of post each channel is limited to. Limits set since startup are kept.
+#[tracing::instrument(skip_all)]
+pub async fn media(db: &Database, media: &DashMap<ChannelId, Kind>) -> Result<()> {
+    let mut cursor = db
+        .collection::<ChannelMedia>(MEDIA)
+        .find(None, None)
+        .await?;
+
+    while let Some(channel) = cursor.try_next().await? {
+        media.entry(channel.channel_id).or_insert(channel.kind);
+    }
+
+    Ok(())
+}
+
+/// Limit a channel to a kind of post, replacing any previous limit.
+#[tracing::instrument(skip(db, channel), fields(channel = %channel.channel_id))]
+pub async fn save_media(db: &Database, channel: &ChannelMedia) -> Result<()> {
+    let timer = Instant::now();
+    db.collection::<ChannelMedia>(MEDIA)
+        .replace_one(
+            doc! { "channelID": channel.channel_id.0.to_string() },
+            channel,
+            ReplaceOptions::builder().upsert(true).build(),
+        )
+        .await?;
+    metrics::mongo("replace_media", timer.elapsed());
+
+    Ok(())
+}
+
+/// Remove a channel's limit on the kind of post.
+#[tracing::instrument(skip(db), fields(channel = %channel_id))]
+pub async fn delete_media(db: &Database, channel_id: ChannelId) -> Result<()> {
+    let timer = Instant::now();
+    db.collection::<ChannelMedia>(MEDIA)
+        .delete_one(doc! { "channelID": channel_id.0.to_string() }, None)
+        .await?;
+    metrics::mongo("delete_media", timer.elapsed());
+
+    Ok(())
+}
+
+/// Load all guilds' post filters. Filters changed since startup are kept.
+#[tracing::instrument(skip_all)]
+pub async fn filters(db: &Database, filters: &DashMap<GuildId, Filter>) -> Result<()> {
+    let mut cursor = db.collection::<Filters>(FILTERS).find(None, None).await?;
+
+    while let Some(config) = cursor.try_next().await? {
+        let guild_id = config.guild_id;
+        match Filter::new(config) {
+            Ok(filter) => {
+                filters.entry(guild_id).or_insert(filter);
+            }
+            Err(e) => error!(guild = %guild_id, "invalid post filters: {e:#}"),
+        }
+    }
+
+    Ok(())
+}
+
+/// Create or replace a guild's post filters.
+#[tracing::instrument(skip_all, fields(guild = %filters.guild_id))]
+pub async fn save_filters(db: &Database, filters: &Filters) -> Result<()> {
+    let timer = Instant::now();
+    db.collection::<Filters>(FILTERS)
+        .replace_one(
+            doc! { "guildID": filters.guild_id.0.to_string() },
+            filters,
+            ReplaceOptions::builder().upsert(true).build(),
+        )
+        .await?;
+    metrics::mongo("replace_filters", timer.elapsed());
+
+    Ok(())
+}
+
+/// Load the bot settings, if they've been saved before.
+#[tracing::instrument(skip_all)]
+pub async fn settings(db: &Database) -> Result<Option<Settings>> {
+    Ok(db
+        .collection::<Settings>(SETTINGS)
+        .find_one(doc! { "_id": SETTINGS_ID }, None)
+        .await?)
+}
+
+/// Load the perceptual hashes of post images. Hashes computed since startup are kept.
+#[cfg(feature = "image-hash")]
+#[tracing::instrument(skip_all)]
+pub async fn image_hashes(db: &Database, hashes: &DashMap<String, u64>) -> Result<()> {
+    let mut cursor = db
+        .collection::<ImageHash>(HASHES)
+        .find(doc! { "time": { "$gte": since(HASH_TTL) } }, None)
+        .await?;
+
+    while let Some(image) = cursor.try_next().await? {
+        hashes.entry(image.key).or_insert(image.hash as u64);
+    }
+
+    Ok(())
+}
+
+/// Save the perceptual hash of a post's image.
+#[cfg(feature = "image-hash")]
+#[tracing::instrument(skip(db))]
+pub async fn save_image_hash(db: &Database, key: &str, hash: u64) -> Result<()> {
+    let timer = Instant::now();
+    db.collection::<ImageHash>(HASHES)
+        .replace_one(
+            doc! { "key": key },
+            ImageHash {
+                key: key.to_string(),
+                hash: hash as i64,
+                time: Utc::now(),
+            },
+            ReplaceOptions::builder().upsert(true).build(),
+        )
+        .await?;
+    metrics::mongo("replace_image_hash", timer.elapsed());
+
+    Ok(())
+}
+
+/// Save the bot settings.
+#[tracing::instrument(skip_all)]
+pub async fn save_settings(db: &Database, settings: &Settings) -> Result<()> {
+    let timer = Instant::now();
+    let mut document = bson::to_document(settings)?;
+    document.insert("_id", SETTINGS_ID);
+
+    db.collection::<Document>(SETTINGS)
+        .replace_one(
+            doc! { "_id": SETTINGS_ID },
+            document,
+            ReplaceOptions::builder().upsert(true).build(),
+        )
+        .await
+        .context("failed to save settings, the change will be lost on restart")?;
+    metrics::mongo("replace_settings", timer.elapsed());
+
+    Ok(())
+}
+
+/// Load all active channels' info from the database.
+#[tracing::instrument(skip_all)]
+pub async fn all_channels(db: &Database, channels: &DashMap<ChannelId, ChannelInfo>) -> Result<()> {
+    // TODO: explicitly type collection if there are no errors deserializing
+    let mut cursor = db.collection(CHANNELS).find(None, None).await?;
+
+    while let Some(doc) = cursor.try_next().await? {
+        match bson::from_bson::<Channel>(Bson::Document(doc)) {
+            Ok(channel) => {
+                channels.entry(channel.channel_id).or_insert(channel.info);
+            }
+            Err(e) => error!("failed to deserialize channel from bson: {e}"),
+        }
+    }
 
-    Ok(channels)
+    Ok(())
 }
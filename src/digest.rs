@@ -0,0 +1,284 @@
+//! Daily digests of top posts.
+
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, NaiveTime, TimeZone, Utc};
+use chrono_tz::Tz;
+use poise::futures_util::future;
+use poise::serenity_prelude::{Context, CreateEmbed};
+use roux::util::{FeedOption, TimePeriod};
+use roux::Subreddit;
+use tracing::{info, warn};
+
+use crate::data::{self, QuickPost};
+use crate::db::{self, Digest};
+use crate::{metrics, posts, Data};
+
+/// Interval between checks for digests that are due.
+const TICK: Duration = Duration::from_secs(30);
+/// Number of top posts fetched from each subreddit.
+const TOP_LIMIT: u32 = 25;
+/// Maximum number of posts in a digest.
+pub const MAX_COUNT: u32 = 10;
+/// Number of recently sent post keys kept to avoid repeats.
+const SENT_LIMIT: usize = 200;
+/// Maximum number of embeds in a message.
+const MESSAGE_EMBEDS: usize = 10;
+/// Maximum total length of the embeds in a message.
+const MESSAGE_EMBEDS_LEN: usize = 6000;
+
+/// Parse a time of day, e.g. `09:00`.
+pub fn parse_time(time: &str) -> Result<NaiveTime> {
+    NaiveTime::parse_from_str(time.trim(), "%H:%M")
+        .map_err(|_| anyhow!("Invalid time `{time}`, use 24-hour `HH:MM`, e.g. `09:00`."))
+}
+
+/// Parse an IANA timezone, e.g. `America/New_York`.
+pub fn parse_timezone(timezone: &str) -> Result<Tz> {
+    timezone
+        .trim()
+        .parse::<Tz>()
+        .map_err(|_| anyhow!("Unknown timezone `{timezone}`, use e.g. `America/New_York`."))
+}
+
+/// The next time after `after` that it's `time` in `timezone`.
+pub fn next(time: NaiveTime, timezone: Tz, after: DateTime<Utc>) -> DateTime<Utc> {
+    let mut date = after.with_timezone(&timezone).date_naive();
+
+    loop {
+        // Times skipped by a DST change are sent an hour later
+        let local = date.and_time(time);
+        let next = timezone
+            .from_local_datetime(&local)
+            .earliest()
+            .or_else(|| {
+                timezone
+                    .from_local_datetime(&(local + chrono::Duration::hours(1)))
+                    .earliest()
+            })
+            .map(|next| next.with_timezone(&Utc));
+
+        match next {
+            Some(next) if next > after => return next,
+            _ => date = date.succ_opt().unwrap_or(date),
+        }
+    }
+}
+
+/// Send digests when they're due, until shutdown.
+pub async fn run(ctx: Context, data: &Data) {
+    let mut ticker = tokio::time::interval(TICK);
+
+    while !data.shutdown.is_stopping() {
+        ticker.tick().await;
+
+        let now = Utc::now();
+        let due = data
+            .digests
+            .iter()
+            .filter(|digest| digest.next <= now)
+            .map(|digest| digest.value().clone())
+            .collect::<Vec<_>>();
+
+        future::join_all(due.into_iter().map(|digest| send(&ctx, data, digest))).await;
+    }
+}
+
+/// Send a digest and schedule the next one.
+#[tracing::instrument(skip_all, fields(channel = %digest.channel_id, group = %digest.group))]
+async fn send(ctx: &Context, data: &Data, mut digest: Digest) {
+    // Digests missed while maintenance mode is enabled are skipped
+    if !data.settings.read().await.maintenance {
+        match top_posts(ctx, data, &digest).await {
+            Ok(posts) if posts.is_empty() => warn!("no new posts for digest"),
+            Ok(posts) => match send_posts(ctx, data, &digest, &posts).await {
+                Ok(_) => {
+                    info!(posts = posts.len(), "sent digest");
+                    digest.sent.extend(posts.into_iter().map(|post| post.key));
+                    let excess = digest.sent.len().saturating_sub(SENT_LIMIT);
+                    digest.sent.drain(..excess);
+                }
+                Err(e) => warn!("failed to send digest: {e:#}"),
+            },
+            Err(e) => warn!("failed to get posts for digest: {e:#}"),
+        }
+    }
+
+    // Digests missed while the bot was offline are only sent once
+    match (parse_time(&digest.time), parse_timezone(&digest.timezone)) {
+        (Ok(time), Ok(timezone)) => digest.next = next(time, timezone, Utc::now()),
+        _ => {
+            warn!("digest has an invalid time or timezone, cancelling it");
+            data.digests
+                .remove(&(digest.channel_id, digest.group.clone()));
+            if let Err(e) = db::delete_digest(&data.db, digest.channel_id, &digest.group).await {
+                warn!("failed to delete digest: {e:#}");
+            }
+            return;
+        }
+    }
+
+    if let Err(e) = db::save_digest(&data.db, &digest).await {
+        warn!("failed to save digest: {e:#}");
+    }
+    // The digest may have been cancelled while sending
+    if let Some(mut entry) = data
+        .digests
+        .get_mut(&(digest.channel_id, digest.group.clone()))
+    {
+        *entry = digest;
+    }
+}
+
+/// Get the highest scoring posts from a group over the past day that can be sent to the digest's
+/// channel, are the kind of post the channel is limited to, pass its guild's filters, and haven't
+/// been sent in a recent digest.
+async fn top_posts(ctx: &Context, data: &Data, digest: &Digest) -> Result<Vec<QuickPost>> {
+    let subs = posts::subs(data, digest.channel_id, &digest.group).await?;
+    let target = posts::target(ctx, data, digest.channel_id).await?;
+    let top = future::join_all(subs.iter().map(|sub| top_today(sub))).await;
+    let filter = target.guild.and_then(|guild| data.filters.get(&guild));
+
+    let mut posts = top
+        .into_iter()
+        .flatten()
+        .filter(|post| target.nsfw || !post.nsfw)
+        .filter(|post| target.kind.is_none_or(|kind| post.kind == kind))
+        .filter(|post| filter.as_ref().is_none_or(|filter| filter.allows(post)))
+        .filter(|post| !digest.sent.contains(&post.key))
+        .collect::<Vec<_>>();
+    posts.sort_unstable_by(|a, b| b.score.total_cmp(&a.score));
+    data::dedup(&mut posts);
+    posts.truncate(digest.count as usize);
+
+    Ok(posts)
+}
+
+/// Get a subreddit's top posts of the day.
+async fn top_today(sub: &str) -> Vec<QuickPost> {
+    let options = FeedOption::new().period(TimePeriod::Today);
+
+    match Subreddit::new(sub).top(TOP_LIMIT, Some(options)).await {
+        Ok(top) => {
+            metrics::reddit_fetch(sub, true);
+            data::submissions_to_quickposts(top)
+        }
+        Err(e) => {
+            metrics::reddit_fetch(sub, false);
+            warn!("failed to get top posts for {sub}: {e}");
+            Vec::new()
+        }
+    }
+}
+
+/// Send posts as embeds, split across as many messages as needed to fit Discord's limits.
+async fn send_posts(
+    ctx: &Context,
+    data: &Data,
+    digest: &Digest,
+    posts: &[QuickPost],
+) -> Result<()> {
+    let mut pages = vec![Vec::new()];
+    let mut len = 0;
+
+    for post in posts {
+        let post_len = embed_len(post);
+        // Unwrap: `pages` is never empty
+        let page = pages.last_mut().unwrap();
+
+        if page.len() == MESSAGE_EMBEDS || len + post_len > MESSAGE_EMBEDS_LEN {
+            pages.push(vec![post]);
+            len = post_len;
+        } else {
+            page.push(post);
+            len += post_len;
+        }
+    }
+
+    let total = pages.len();
+    for (i, page) in pages.into_iter().enumerate() {
+        let embeds = page
+            .into_iter()
+            .map(|post| {
+                let mut e = CreateEmbed::default();
+                posts::embed(data, post, &mut e);
+                e
+            })
+            .collect();
+        let header = if total > 1 {
+            format!("**Daily {} digest** ({}/{total})", digest.group, i + 1)
+        } else {
+            format!("**Daily {} digest**", digest.group)
+        };
+
+        digest
+            .channel_id
+            .send_message(ctx, |msg| msg.content(header).add_embeds(embeds))
+            .await?;
+    }
+
+    Ok(())
+}
+
+/// Upper bound of the length of a post's embed.
+fn embed_len(post: &QuickPost) -> usize {
+    // Footer and title, plus the content as a description even if it's shown as an image
+    64 + post.title.chars().count().min(256) + post.content.chars().count().min(4096)
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono_tz::America::New_York;
+
+    use super::*;
+
+    fn utc(month: u32, day: u32, hour: u32, min: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2024, month, day, hour, min, 0)
+            .unwrap()
+    }
+
+    #[test]
+    fn next_is_later_today_or_tomorrow() {
+        let time = parse_time("09:00").unwrap();
+
+        // 08:00 and 09:00 EST
+        assert_eq!(next(time, New_York, utc(1, 15, 13, 0)), utc(1, 15, 14, 0));
+        assert_eq!(next(time, New_York, utc(1, 15, 14, 0)), utc(1, 16, 14, 0));
+    }
+
+    #[test]
+    fn next_follows_offset_changes() {
+        let time = parse_time("09:00").unwrap();
+
+        // 10:00 EST the day before clocks go forward, then 09:00 EDT
+        assert_eq!(next(time, New_York, utc(3, 9, 15, 0)), utc(3, 10, 13, 0));
+        // 10:00 EDT the day before clocks go back, then 09:00 EST
+        assert_eq!(next(time, New_York, utc(11, 2, 14, 0)), utc(11, 3, 14, 0));
+    }
+
+    #[test]
+    fn skipped_times_are_an_hour_later() {
+        // 02:30 doesn't exist when clocks go forward, so 03:30 EDT
+        let time = parse_time("02:30").unwrap();
+        assert_eq!(next(time, New_York, utc(3, 10, 5, 0)), utc(3, 10, 7, 30));
+    }
+
+    #[test]
+    fn repeated_times_are_sent_once() {
+        // 01:30 happens twice when clocks go back, first in EDT
+        let time = parse_time("01:30").unwrap();
+        assert_eq!(next(time, New_York, utc(11, 3, 4, 0)), utc(11, 3, 5, 30));
+        // Then the next day in EST, not again an hour later
+        assert_eq!(next(time, New_York, utc(11, 3, 5, 30)), utc(11, 4, 6, 30));
+    }
+
+    #[test]
+    fn parses_times_and_timezones() {
+        assert!(parse_time(" 23:59 ").is_ok());
+        assert!(parse_time("24:00").is_err());
+        assert!(parse_time("9am").is_err());
+        assert_eq!(parse_timezone("America/New_York").unwrap(), New_York);
+        assert!(parse_timezone("Eastern").is_err());
+    }
+}
@@ -0,0 +1,196 @@
+//! r/fiftyfifty posts, whose links are hidden until revealed.
+
+use anyhow::Result;
+use poise::serenity_prelude::{
+    ButtonStyle, Context, CreateActionRow, CreateEmbed, InteractionResponseType,
+    MessageComponentInteraction,
+};
+
+use crate::data::{self, QuickPost};
+use crate::posts;
+
+/// Custom ID of reveal buttons. The link to reveal is read back from the message's embed, so
+/// buttons keep working after restarts.
+pub const REVEAL_ID: &str = "fiftyfifty-reveal";
+
+/// The two outcomes in a `[50/50] A | B` title.
+#[derive(Debug)]
+pub struct Outcomes<'a>(&'a str, &'a str);
+
+impl<'a> Outcomes<'a> {
+    /// Parse the outcomes from a title, ignoring case and spacing in the `[50/50]` tag.
+    pub fn parse(title: &'a str) -> Option<Self> {
+        let rest = title.trim_start().strip_prefix('[')?;
+        let (tag, rest) = rest.split_once(']')?;
+        if tag.split_whitespace().collect::<String>() != "50/50" {
+            return None;
+        }
+
+        let (a, b) = rest.split_once('|')?;
+        let (a, b) = (a.trim(), b.trim());
+        (!a.is_empty() && !b.is_empty()).then_some(Self(a, b))
+    }
+}
+
+/// Whether a post is from a subreddit in the fiftyfifty group.
+pub fn is_fiftyfifty(post: &QuickPost) -> bool {
+    data::in_group(data::FIFTYFIFTY_GROUP, &post.sub)
+}
+
+/// Render a post with its outcomes and its link hidden in spoiler tags, so the link's preview
+/// doesn't give it away.
+pub fn embed<'a>(post: &QuickPost, e: &'a mut CreateEmbed) -> &'a mut CreateEmbed {
+    let mut description = String::new();
+    if post.nsfw {
+        description.push_str("⚠️ **This post is marked NSFW, either outcome may be graphic.**\n\n");
+    }
+    match Outcomes::parse(&post.title) {
+        Some(Outcomes(a, b)) => description.push_str(&format!("**{a}**\nor\n**{b}**")),
+        None => description.push_str(&post.title),
+    }
+    description.push_str(&format!("\n\n||{}||", post.content));
+
+    e.title("50/50")
+        .url(posts::url(post))
+        .description(description)
+        .footer(|f| f.text(posts::footer(post)))
+}
+
+/// Create the button that reveals a post's link.
+pub fn reveal_button(row: &mut CreateActionRow) -> &mut CreateActionRow {
+    row.create_button(|b| {
+        b.custom_id(REVEAL_ID)
+            .label("Reveal")
+            .style(ButtonStyle::Danger)
+    })
+}
+
+/// Post the link hidden in a message's embed to the channel, and remove the reveal button from
+/// messages that have no other buttons.
+#[tracing::instrument(skip_all, fields(
+    channel = %interaction.channel_id,
+    user = %interaction.user.id,
+))]
+pub async fn reveal(ctx: &Context, interaction: &MessageComponentInteraction) -> Result<()> {
+    let link = interaction
+        .message
+        .embeds
+        .first()
+        .and_then(|embed| embed.description.as_deref())
+        .and_then(hidden_link);
+
+    interaction
+        .create_interaction_response(ctx, |r| {
+            r.kind(InteractionResponseType::ChannelMessageWithSource)
+                .interaction_response_data(|d| match link {
+                    Some(link) => d.content(format!("{} revealed {link}", interaction.user)),
+                    None => d.content("This post can't be revealed.").ephemeral(true),
+                })
+        })
+        .await?;
+
+    // Paginated posts have their own buttons, which are replaced on the next page
+    if link.is_some() && interaction.message.components.len() == 1 {
+        interaction
+            .message
+            .clone()
+            .edit(ctx, |m| m.components(|c| c))
+            .await?;
+    }
+
+    Ok(())
+}
+
+/// Get the link in the last spoiler tags of a description.
+fn hidden_link(description: &str) -> Option<&str> {
+    let (rest, _) = description.trim_end().rsplit_once("||")?;
+    let (_, link) = rest.rsplit_once("||")?;
+
+    (link.starts_with("https://") || link.starts_with("http://")).then_some(link)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data::Kind;
+
+    fn outcomes(title: &str) -> Option<(&str, &str)> {
+        Outcomes::parse(title).map(|Outcomes(a, b)| (a, b))
+    }
+
+    fn post(title: &str, nsfw: bool) -> QuickPost {
+        QuickPost {
+            title: title.to_string(),
+            score: 1.0,
+            content: "https://i.redd.it/abc.jpg".to_string(),
+            nsfw,
+            permalink: "/r/FiftyFifty/comments/abc/".to_string(),
+            sub: "FiftyFifty".to_string(),
+            flair: None,
+            spoiler: false,
+            kind: Kind::Image,
+            domain: "i.redd.it".to_string(),
+            created: 0,
+            key: String::new(),
+        }
+    }
+
+    fn description(post: &QuickPost) -> String {
+        let mut e = CreateEmbed::default();
+        embed(post, &mut e);
+        e.0["description"].as_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn parses_outcomes() {
+        assert_eq!(
+            outcomes("[50/50] Cute Puppy | Spider"),
+            Some(("Cute Puppy", "Spider"))
+        );
+        assert_eq!(outcomes("  [ 50 / 50 ]a|b "), Some(("a", "b")));
+        // Only the first separator splits the outcomes
+        assert_eq!(outcomes("[50/50] a | b | c"), Some(("a", "b | c")));
+    }
+
+    #[test]
+    fn rejects_malformed_titles() {
+        assert_eq!(outcomes("50/50 a | b"), None);
+        assert_eq!(outcomes("[50/51] a | b"), None);
+        assert_eq!(outcomes("[50/50 a | b"), None);
+        assert_eq!(outcomes("[50/50] a or b"), None);
+        assert_eq!(outcomes("[50/50] a | "), None);
+        assert_eq!(outcomes("[50/50] | b"), None);
+        assert_eq!(outcomes("a | b [50/50]"), None);
+    }
+
+    #[test]
+    fn hides_link_in_spoiler() {
+        let description = description(&post("[50/50] Cake | Bugs", false));
+
+        assert_eq!(
+            description,
+            "**Cake**\nor\n**Bugs**\n\n||https://i.redd.it/abc.jpg||"
+        );
+        assert_eq!(hidden_link(&description), Some("https://i.redd.it/abc.jpg"));
+    }
+
+    #[test]
+    fn keeps_unparsed_titles() {
+        let description = description(&post("Not a | real || 50/50", true));
+
+        assert!(description.starts_with("⚠️"));
+        assert!(description.contains("Not a | real || 50/50"));
+        assert_eq!(hidden_link(&description), Some("https://i.redd.it/abc.jpg"));
+    }
+
+    #[test]
+    fn finds_hidden_links() {
+        assert_eq!(
+            hidden_link("a ||spoiler|| b\n\n||http://example.com/a||\n"),
+            Some("http://example.com/a")
+        );
+        assert_eq!(hidden_link("**a**\nor\n**b**\n\n||not a link||"), None);
+        assert_eq!(hidden_link("||https://example.com/a"), None);
+        assert_eq!(hidden_link("no spoilers"), None);
+    }
+}
@@ -0,0 +1,230 @@
+//! Per-guild post filters.
+
+use anyhow::{anyhow, Result};
+use regex::{Regex, RegexBuilder};
+
+use crate::data::QuickPost;
+use crate::db::Filters;
+use crate::news;
+
+/// Maximum compiled size of a title regex, to keep user-supplied patterns cheap.
+const PATTERN_SIZE_LIMIT: usize = 1 << 16;
+
+/// A guild's post filters, with their title regexes compiled.
+#[derive(Debug, Clone)]
+pub struct Filter {
+    pub config: Filters,
+    patterns: Vec<Regex>,
+}
+
+impl Filter {
+    pub fn new(config: Filters) -> Result<Self> {
+        let patterns = config
+            .blocked_patterns
+            .iter()
+            .map(|pattern| compile(pattern))
+            .collect::<Result<_>>()?;
+
+        Ok(Self { config, patterns })
+    }
+
+    /// Whether a post passes the filters.
+    pub fn allows(&self, post: &QuickPost) -> bool {
+        let config = &self.config;
+        let title = post.title.to_lowercase();
+        let flair = post.flair.as_deref().unwrap_or_default();
+
+        if config.min_score.is_some_and(|min| post.score < min)
+            || (config.block_spoilers && post.spoiler)
+        {
+            return false;
+        }
+        if config
+            .blocked_keywords
+            .iter()
+            .any(|keyword| title.contains(&keyword.to_lowercase()))
+            || self
+                .patterns
+                .iter()
+                .any(|pattern| pattern.is_match(&post.title))
+        {
+            return false;
+        }
+        if config
+            .blocked_flairs
+            .iter()
+            .any(|blocked| blocked.eq_ignore_ascii_case(flair))
+        {
+            return false;
+        }
+        if news::is_news(post) {
+            let listed = |domains: &[String]| {
+                domains
+                    .iter()
+                    .any(|domain| news::domain_matches(&post.domain, domain))
+            };
+            if listed(&config.blocked_domains)
+                || (!config.allowed_domains.is_empty() && !listed(&config.allowed_domains))
+            {
+                return false;
+            }
+        }
+
+        config.required_flairs.is_empty()
+            || config
+                .required_flairs
+                .iter()
+                .any(|required| required.eq_ignore_ascii_case(flair))
+    }
+}
+
+/// Compile a case insensitive title regex.
+pub fn compile(pattern: &str) -> Result<Regex> {
+    RegexBuilder::new(pattern)
+        .case_insensitive(true)
+        .size_limit(PATTERN_SIZE_LIMIT)
+        .build()
+        .map_err(|e| anyhow!("Invalid regex `{pattern}`: {e}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use poise::serenity_prelude::GuildId;
+
+    use super::*;
+    use crate::data::{self, Kind};
+
+    fn post(title: &str) -> QuickPost {
+        QuickPost {
+            title: title.to_string(),
+            score: 100.0,
+            content: "https://i.redd.it/abc.png".to_string(),
+            nsfw: false,
+            permalink: "/r/memes/comments/abc/".to_string(),
+            sub: "memes".to_string(),
+            flair: None,
+            spoiler: false,
+            kind: Kind::Image,
+            domain: "i.redd.it".to_string(),
+            created: 0,
+            key: String::new(),
+        }
+    }
+
+    fn news(domain: &str) -> QuickPost {
+        data::SUBS.insert(data::NEWS_GROUP.to_string(), vec!["filternews".to_string()]);
+
+        QuickPost {
+            sub: "FilterNews".to_string(),
+            kind: Kind::Link,
+            content: format!("https://{domain}/article"),
+            domain: domain.to_string(),
+            ..post("Something happened")
+        }
+    }
+
+    fn filter(configure: impl FnOnce(&mut Filters)) -> Filter {
+        let mut config = Filters::new(GuildId(1));
+        configure(&mut config);
+        Filter::new(config).unwrap()
+    }
+
+    #[test]
+    fn default_allows_everything() {
+        let filter = filter(|_| ());
+
+        assert!(filter.allows(&post("anything")));
+        assert!(filter.allows(&QuickPost {
+            spoiler: true,
+            score: -5.0,
+            ..post("anything")
+        }));
+    }
+
+    #[test]
+    fn blocks_low_scores_and_spoilers() {
+        let filter = filter(|config| {
+            config.min_score = Some(50.0);
+            config.block_spoilers = true;
+        });
+
+        assert!(filter.allows(&post("meme")));
+        assert!(filter.allows(&QuickPost {
+            score: 50.0,
+            ..post("meme")
+        }));
+        assert!(!filter.allows(&QuickPost {
+            score: 49.0,
+            ..post("meme")
+        }));
+        assert!(!filter.allows(&QuickPost {
+            spoiler: true,
+            ..post("meme")
+        }));
+    }
+
+    #[test]
+    fn blocks_keywords_and_patterns() {
+        let filter = filter(|config| {
+            config.blocked_keywords = vec!["Politics".to_string()];
+            config.blocked_patterns = vec![r"^\[oc\]".to_string()];
+        });
+
+        assert!(filter.allows(&post("cat picture")));
+        assert!(!filter.allows(&post("no POLITICS please")));
+        assert!(!filter.allows(&post("[OC] my drawing")));
+        assert!(filter.allows(&post("my drawing [OC]")));
+    }
+
+    #[test]
+    fn blocks_and_requires_flairs() {
+        let flaired = |flair: &str| QuickPost {
+            flair: Some(flair.to_string()),
+            ..post("meme")
+        };
+        let blocked = filter(|config| config.blocked_flairs = vec!["NSFW".to_string()]);
+        let required = filter(|config| {
+            config.required_flairs = vec!["OC".to_string(), "Meme".to_string()];
+        });
+
+        assert!(!blocked.allows(&flaired("nsfw")));
+        assert!(blocked.allows(&flaired("meme")));
+        assert!(blocked.allows(&post("meme")));
+
+        assert!(required.allows(&flaired("oc")));
+        assert!(required.allows(&flaired("MEME")));
+        assert!(!required.allows(&flaired("repost")));
+        assert!(!required.allows(&post("meme")));
+    }
+
+    #[test]
+    fn filters_news_domains() {
+        let blocked = filter(|config| config.blocked_domains = vec!["tabloid.com".to_string()]);
+        let allowed = filter(|config| config.allowed_domains = vec!["bbc.com".to_string()]);
+
+        assert!(!blocked.allows(&news("tabloid.com")));
+        assert!(!blocked.allows(&news("www.tabloid.com")));
+        assert!(blocked.allows(&news("bbc.com")));
+
+        assert!(allowed.allows(&news("news.bbc.com")));
+        assert!(!allowed.allows(&news("evilbbc.com")));
+        // Domain filters only apply to news subreddits
+        assert!(allowed.allows(&post("meme")));
+    }
+
+    #[test]
+    fn rejects_invalid_patterns() {
+        let invalid = Filters {
+            blocked_patterns: vec!["(unclosed".to_string()],
+            ..Filters::new(GuildId(1))
+        };
+        let oversized = Filters {
+            blocked_patterns: vec![r"\w{1000}".to_string()],
+            ..Filters::new(GuildId(1))
+        };
+
+        assert!(Filter::new(invalid).is_err());
+        assert!(Filter::new(oversized).is_err());
+        assert!(compile(r"^\[oc\] .+").is_ok());
+    }
+}
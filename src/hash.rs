@@ -0,0 +1,327 @@
+//! Perceptual hashes of post images, enabled with the `image-hash` feature, so reposts of an image
+//! are caught even when their URLs differ. Without the feature, no posts are reposts.
+
+#[cfg(feature = "image-hash")]
+pub use enabled::*;
+
+#[cfg(not(feature = "image-hash"))]
+pub use disabled::*;
+
+#[cfg(feature = "image-hash")]
+mod enabled {
+    use std::collections::{HashMap, HashSet};
+    use std::time::{Duration, Instant};
+
+    use anyhow::{bail, Result};
+    use dashmap::DashMap;
+    use image::imageops::FilterType;
+    use poise::futures_util::{stream, StreamExt};
+    use tracing::{debug, info, warn};
+
+    use crate::{db, Data, Subsystem};
+
+    /// Interval between checks for images that haven't been hashed.
+    const TICK: Duration = Duration::from_secs(60);
+    /// Timeout of image downloads.
+    const TIMEOUT: Duration = Duration::from_secs(10);
+    /// Maximum size of a downloaded image.
+    const MAX_IMAGE_LEN: usize = 16 * 1024 * 1024;
+    /// Number of images downloaded at a time.
+    const CONCURRENCY: usize = 4;
+    /// How long until an image that couldn't be hashed is tried again.
+    const RETRY_AFTER: Duration = Duration::from_secs(6 * 60 * 60);
+    /// Maximum number of differing bits between the hashes of nearly identical images.
+    const MAX_DISTANCE: u32 = 6;
+
+    /// Perceptual hashes of post images, by post key.
+    #[derive(Debug)]
+    pub struct Hashes {
+        client: reqwest::Client,
+        hashes: DashMap<String, u64>,
+        /// Keys of posts whose images couldn't be downloaded or decoded, and when they can be
+        /// tried again.
+        failed: DashMap<String, Instant>,
+    }
+
+    impl Hashes {
+        pub fn new() -> Result<Self> {
+            Ok(Self {
+                client: reqwest::Client::builder()
+                    .timeout(TIMEOUT)
+                    .user_agent(concat!("memer/", env!("CARGO_PKG_VERSION")))
+                    .build()?,
+                hashes: DashMap::new(),
+                failed: DashMap::new(),
+            })
+        }
+
+        /// Get the hashes of the images of posts that have been hashed, by key.
+        pub fn get_all<'a>(&self, keys: impl IntoIterator<Item = &'a str>) -> Vec<u64> {
+            keys.into_iter()
+                .filter_map(|key| self.hashes.get(key).map(|hash| *hash))
+                .collect()
+        }
+
+        /// Whether a post's image is nearly identical to an image with one of `hashes`.
+        pub fn is_repost(&self, key: &str, hashes: &[u64]) -> bool {
+            if hashes.is_empty() {
+                return false;
+            }
+
+            self.hashes.get(key).is_some_and(|hash| {
+                hashes
+                    .iter()
+                    .any(|other| (*hash ^ other).count_ones() <= MAX_DISTANCE)
+            })
+        }
+
+        /// Download an image and compute its hash.
+        async fn compute(&self, url: &str) -> Result<u64> {
+            let mut res = self.client.get(url).send().await?.error_for_status()?;
+
+            let mut bytes = Vec::new();
+            while let Some(chunk) = res.chunk().await? {
+                bytes.extend_from_slice(&chunk);
+                if bytes.len() > MAX_IMAGE_LEN {
+                    bail!("image is too large");
+                }
+            }
+
+            // Decoding and resizing is CPU bound
+            tokio::task::spawn_blocking(move || dhash(&bytes)).await?
+        }
+
+        /// Forget the hashes of posts that aren't `live`, and failures that can be tried again.
+        fn evict(&self, live: &HashSet<String>) {
+            let now = Instant::now();
+
+            self.hashes.retain(|key, _| live.contains(key));
+            self.failed
+                .retain(|key, retry| live.contains(key) && *retry > now);
+        }
+    }
+
+    /// Keys of posts whose hashes are still needed: cached posts, and posts sent to channels that
+    /// reposts are checked against.
+    fn live_keys(data: &Data) -> HashSet<String> {
+        let mut live = HashSet::new();
+        for posts in data.posts.iter() {
+            live.extend(posts.iter().map(|post| post.key.clone()));
+        }
+        for blacklist in data.blacklist.iter() {
+            live.extend(blacklist.iter().map(|entry| entry.key.clone()));
+        }
+        live.extend(data.last_post.iter().map(|post| post.key.clone()));
+
+        live
+    }
+
+    /// Compute the difference hash of an image: each bit is whether a pixel is darker than the
+    /// pixel to its right, in a 9x8 grayscale thumbnail.
+    pub fn dhash(bytes: &[u8]) -> Result<u64> {
+        let thumbnail = image::load_from_memory(bytes)?
+            .resize_exact(9, 8, FilterType::Triangle)
+            .to_luma8();
+
+        let mut hash = 0;
+        for y in 0..8 {
+            for x in 0..8 {
+                let darker = thumbnail.get_pixel(x, y).0[0] < thumbnail.get_pixel(x + 1, y).0[0];
+                hash = (hash << 1) | u64::from(darker);
+            }
+        }
+
+        Ok(hash)
+    }
+
+    /// Hash the images of cached posts that haven't been hashed, until shutdown. Hashes are saved
+    /// to the database, so images aren't hashed again after a restart.
+    pub async fn run(data: &Data) {
+        let hashes = &data.hashes;
+        let mut ticker = tokio::time::interval(TICK);
+        let mut loaded = false;
+
+        while !data.shutdown.is_stopping() {
+            ticker.tick().await;
+
+            // Saved hashes are loaded before anything is hashed, so images hashed before a
+            // restart aren't downloaded again
+            if !loaded {
+                if !data.ready.get(Subsystem::Database) {
+                    continue;
+                }
+                if let Err(e) = db::image_hashes(&data.db, &hashes.hashes).await {
+                    warn!("failed to load image hashes: {e:#}");
+                }
+                loaded = true;
+            }
+            // Hashes of posts that aren't cached yet would be evicted along with stale ones
+            if data.ready.get(Subsystem::Posts) {
+                hashes.evict(&live_keys(data));
+            }
+
+            let missing = data
+                .posts
+                .iter()
+                .flat_map(|posts| {
+                    posts
+                        .iter()
+                        .filter(|post| post.image().is_some())
+                        .filter(|post| {
+                            !hashes.hashes.contains_key(&post.key)
+                                && !hashes.failed.contains_key(&post.key)
+                        })
+                        .map(|post| (post.key.clone(), post.content.clone()))
+                        .collect::<Vec<_>>()
+                })
+                .collect::<HashMap<_, _>>();
+            if missing.is_empty() {
+                continue;
+            }
+            info!(images = missing.len(), "hashing images");
+
+            stream::iter(missing)
+                .for_each_concurrent(CONCURRENCY, |(key, url)| async move {
+                    if data.shutdown.is_stopping() {
+                        return;
+                    }
+
+                    match hashes.compute(&url).await {
+                        Ok(hash) => {
+                            if let Err(e) = db::save_image_hash(&data.db, &key, hash).await {
+                                warn!("failed to save image hash: {e:#}");
+                            }
+                            hashes.hashes.insert(key, hash);
+                        }
+                        Err(e) => {
+                            debug!("failed to hash {url}: {e:#}");
+                            hashes.failed.insert(key, Instant::now() + RETRY_AFTER);
+                        }
+                    }
+                })
+                .await;
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use std::io::Cursor;
+
+        use image::{DynamicImage, ImageFormat, RgbImage};
+
+        use super::*;
+
+        /// A smooth test pattern, optionally mirrored left to right.
+        fn pattern(width: u32, height: u32, mirrored: bool) -> DynamicImage {
+            DynamicImage::ImageRgb8(RgbImage::from_fn(width, height, |x, y| {
+                let x = if mirrored { width - 1 - x } else { x };
+                let (fx, fy) = (x as f64 / width as f64, y as f64 / height as f64);
+                let v = (100.0 * (fx * 9.0).sin()).mul_add(fy.mul_add(5.0, fx * 2.0).cos(), 128.0);
+                let v = v as u8;
+                image::Rgb([v, v / 2, 255 - v])
+            }))
+        }
+
+        fn encode(image: &DynamicImage, format: ImageFormat) -> Vec<u8> {
+            let mut bytes = Cursor::new(Vec::new());
+            image.write_to(&mut bytes, format).unwrap();
+            bytes.into_inner()
+        }
+
+        fn distance(a: u64, b: u64) -> u32 {
+            (a ^ b).count_ones()
+        }
+
+        #[test]
+        fn identical_images_have_equal_hashes() {
+            let png = encode(&pattern(256, 256, false), ImageFormat::Png);
+            assert_eq!(dhash(&png).unwrap(), dhash(&png.clone()).unwrap());
+        }
+
+        #[test]
+        fn resized_and_reencoded_images_are_near() {
+            let original = dhash(&encode(&pattern(256, 256, false), ImageFormat::Png)).unwrap();
+            let resized = dhash(&encode(&pattern(120, 120, false), ImageFormat::Png)).unwrap();
+            let jpeg = dhash(&encode(&pattern(256, 256, false), ImageFormat::Jpeg)).unwrap();
+
+            assert!(distance(original, resized) <= MAX_DISTANCE);
+            assert!(distance(original, jpeg) <= MAX_DISTANCE);
+        }
+
+        #[test]
+        fn different_images_are_far() {
+            let original = dhash(&encode(&pattern(256, 256, false), ImageFormat::Png)).unwrap();
+            let mirrored = dhash(&encode(&pattern(256, 256, true), ImageFormat::Png)).unwrap();
+
+            assert!(distance(original, mirrored) > MAX_DISTANCE);
+        }
+
+        #[test]
+        fn rejects_invalid_images() {
+            assert!(dhash(b"not an image").is_err());
+        }
+
+        #[test]
+        fn reposts_are_within_max_distance() {
+            let hashes = Hashes::new().unwrap();
+            hashes.hashes.insert("post".to_string(), 0);
+
+            let near = (1 << MAX_DISTANCE) - 1;
+            let far = (1 << (MAX_DISTANCE + 1)) - 1;
+            assert!(hashes.is_repost("post", &[far, near]));
+            assert!(!hashes.is_repost("post", &[far]));
+            assert!(!hashes.is_repost("post", &[]));
+            assert!(!hashes.is_repost("other", &[0]));
+        }
+
+        #[test]
+        fn evicts_hashes_that_arent_live() {
+            let hashes = Hashes::new().unwrap();
+            let live = ["cached".to_string(), "failed".to_string()].into();
+            hashes.hashes.insert("cached".to_string(), 1);
+            hashes.hashes.insert("gone".to_string(), 2);
+            hashes
+                .failed
+                .insert("failed".to_string(), Instant::now() + RETRY_AFTER);
+            hashes
+                .failed
+                .insert("gone".to_string(), Instant::now() + RETRY_AFTER);
+
+            hashes.evict(&live);
+            assert_eq!(hashes.get_all(["cached", "gone"]), [1]);
+            assert!(hashes.failed.contains_key("failed"));
+            assert!(!hashes.failed.contains_key("gone"));
+
+            // Failures can be retried once they expire, even if they're still live
+            hashes.failed.insert("failed".to_string(), Instant::now());
+            hashes.evict(&live);
+            assert!(hashes.failed.is_empty());
+        }
+    }
+}
+
+#[cfg(not(feature = "image-hash"))]
+mod disabled {
+    use anyhow::Result;
+
+    use crate::Data;
+
+    #[derive(Debug)]
+    pub struct Hashes;
+
+    impl Hashes {
+        pub const fn new() -> Result<Self> {
+            Ok(Self)
+        }
+
+        pub fn get_all<'a>(&self, _keys: impl IntoIterator<Item = &'a str>) -> Vec<u64> {
+            Vec::new()
+        }
+
+        pub const fn is_repost(&self, _key: &str, _hashes: &[u64]) -> bool {
+            false
+        }
+    }
+
+    pub async fn run(_data: &Data) {}
+}
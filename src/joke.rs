@@ -0,0 +1,35 @@
+//! Text group posts, rendered as jokes.
+
+use poise::serenity_prelude::CreateEmbed;
+
+use crate::data::{self, QuickPost};
+use crate::posts;
+
+/// Room left in the description for the link to the rest of a cut off punchline.
+const READ_MORE_LEN: usize = 128;
+
+/// Whether a post is from a subreddit in the text group.
+pub fn is_joke(post: &QuickPost) -> bool {
+    data::in_group(data::TEXT_GROUP, &post.sub)
+}
+
+/// Render a post with its title as the setup and its text as a punchline hidden in spoiler tags.
+/// Long punchlines are cut off with a link to the rest.
+pub fn embed<'a>(post: &QuickPost, e: &'a mut CreateEmbed) -> &'a mut CreateEmbed {
+    // Spoiler tags can't be nested
+    let punchline = post.content.replace("||", "\\|\\|");
+    let punchline = if punchline.chars().count() > posts::DESCRIPTION_LEN - 4 {
+        format!(
+            "||{}||\n[Read the rest]({})",
+            posts::truncate(&punchline, posts::DESCRIPTION_LEN - 4 - READ_MORE_LEN),
+            posts::url(post)
+        )
+    } else {
+        format!("||{punchline}||")
+    };
+
+    e.title(posts::truncate(&post.title, posts::TITLE_LEN))
+        .url(posts::url(post))
+        .description(punchline)
+        .footer(|f| f.text(posts::footer(post)))
+}
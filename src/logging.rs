@@ -0,0 +1,245 @@
+//! Logging setup.
+
+use std::path::{Path, PathBuf};
+use std::{env, fs};
+
+use anyhow::{bail, Context, Result};
+use tracing_appender::non_blocking::WorkerGuard;
+use tracing_appender::rolling::{RollingFileAppender, Rotation};
+use tracing_subscriber::fmt::MakeWriter;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{EnvFilter, Layer, Registry};
+
+use crate::setup;
+
+/// Default number of rotated log files to keep.
+const RETENTION: usize = 7;
+
+/// Log output format.
+#[derive(Debug, Clone, Copy)]
+enum Format {
+    Full,
+    Pretty,
+    Compact,
+    Json,
+}
+
+/// Flushes buffered log file output and exports remaining trace spans when dropped.
+pub struct Guard {
+    _file: Option<WorkerGuard>,
+    #[cfg(feature = "otel")]
+    tracer_provider: Option<opentelemetry_sdk::trace::SdkTracerProvider>,
+}
+
+#[cfg(feature = "otel")]
+impl Drop for Guard {
+    fn drop(&mut self) {
+        if let Some(provider) = self.tracer_provider.take() {
+            if let Err(e) = provider.shutdown() {
+                eprintln!("failed to export remaining trace spans: {e}");
+            }
+        }
+    }
+}
+
+/// Initialize logging to stdout, to rolling log files if `MEMER_LOG_DIR` is set, and to an
+/// OpenTelemetry collector with the `otel` feature. The returned guard must be held until the bot
+/// exits.
+pub fn init() -> Result<Guard> {
+    if env::var("MEMER_LOG").is_err() {
+        env::set_var("MEMER_LOG", "INFO");
+    }
+
+    let mut filter = EnvFilter::from_env("MEMER_LOG");
+    // Per-module filters on top of the default level, e.g. "memer::db=debug,serenity=warn"
+    if let Ok(directives) = env::var("MEMER_LOG_FILTER") {
+        for directive in directives.split(',').filter(|d| !d.trim().is_empty()) {
+            filter = filter.add_directive(
+                directive
+                    .trim()
+                    .parse()
+                    .with_context(|| format!("invalid MEMER_LOG_FILTER directive: {directive}"))?,
+            );
+        }
+    }
+
+    let format = match env::var("MEMER_LOG_FORMAT").as_deref() {
+        Ok("full") | Err(_) => Format::Full,
+        Ok("pretty") => Format::Pretty,
+        Ok("compact") => Format::Compact,
+        Ok("json") => Format::Json,
+        Ok(_) => bail!("invalid MEMER_LOG_FORMAT environment variable"),
+    };
+
+    let mut layers = vec![layer(format, std::io::stdout, true)];
+    let file = setup::env_parse::<PathBuf>("MEMER_LOG_DIR")?
+        .map(|dir| file_layer(format, &dir))
+        .transpose()?
+        .map(|(layer, guard)| {
+            layers.push(layer);
+            guard
+        });
+
+    #[cfg(feature = "otel")]
+    let tracer_provider = {
+        let endpoint = env::var("MEMER_OTEL_ENDPOINT")
+            .unwrap_or_else(|_| "http://localhost:4318/v1/traces".to_string());
+        let service_name =
+            env::var("MEMER_OTEL_SERVICE_NAME").unwrap_or_else(|_| "memer".to_string());
+        let (layer, provider) = otel_layer(endpoint, service_name)?;
+        layers.push(layer);
+        Some(provider)
+    };
+
+    tracing_subscriber::registry()
+        .with(layers)
+        .with(filter)
+        .try_init()
+        .context("failed to initialize logging")?;
+
+    Ok(Guard {
+        _file: file,
+        #[cfg(feature = "otel")]
+        tracer_provider,
+    })
+}
+
+/// Create a formatting layer that writes to rolling log files in `dir`.
+fn file_layer(
+    format: Format,
+    dir: &Path,
+) -> Result<(Box<dyn Layer<Registry> + Send + Sync>, WorkerGuard)> {
+    let rotation = match env::var("MEMER_LOG_ROTATION").as_deref() {
+        Ok("daily") | Err(_) => Rotation::DAILY,
+        Ok("hourly") => Rotation::HOURLY,
+        Ok("never") => Rotation::NEVER,
+        Ok(_) => bail!("invalid MEMER_LOG_ROTATION environment variable"),
+    };
+    fs::create_dir_all(dir)
+        .with_context(|| format!("failed to create directory: {}", dir.display()))?;
+    let appender = RollingFileAppender::builder()
+        .rotation(rotation)
+        .filename_prefix("memer")
+        .filename_suffix("log")
+        .max_log_files(setup::env_parse("MEMER_LOG_RETENTION")?.unwrap_or(RETENTION))
+        .build(dir)
+        .with_context(|| format!("failed to create log file in: {}", dir.display()))?;
+    let (writer, guard) = tracing_appender::non_blocking(appender);
+
+    Ok((layer(format, writer, false), guard))
+}
+
+/// Create a layer that exports spans over OTLP/HTTP to the collector at `endpoint`.
+#[cfg(feature = "otel")]
+fn otel_layer(
+    endpoint: String,
+    service_name: String,
+) -> Result<(
+    Box<dyn Layer<Registry> + Send + Sync>,
+    opentelemetry_sdk::trace::SdkTracerProvider,
+)> {
+    use opentelemetry::trace::TracerProvider as _;
+    use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+    use opentelemetry_sdk::trace::SdkTracerProvider;
+    use opentelemetry_sdk::Resource;
+
+    let exporter = SpanExporter::builder()
+        .with_http()
+        .with_endpoint(endpoint)
+        .build()
+        .context("failed to create OTLP span exporter")?;
+    let provider = SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_resource(Resource::builder().with_service_name(service_name).build())
+        .build();
+    let layer = tracing_opentelemetry::layer()
+        .with_tracer(provider.tracer("memer"))
+        .boxed();
+
+    Ok((layer, provider))
+}
+
+/// Create a formatting layer that writes to `writer`.
+fn layer<W>(format: Format, writer: W, ansi: bool) -> Box<dyn Layer<Registry> + Send + Sync>
+where
+    W: for<'w> MakeWriter<'w> + Send + Sync + 'static,
+{
+    let layer = tracing_subscriber::fmt::layer()
+        .with_writer(writer)
+        .with_ansi(ansi);
+
+    match format {
+        Format::Full => layer.with_target(false).boxed(),
+        Format::Pretty => layer.with_target(false).pretty().boxed(),
+        Format::Compact => layer.with_target(false).compact().boxed(),
+        // Keep the target so logs can be queried by module
+        Format::Json => layer
+            .json()
+            .with_current_span(true)
+            .with_span_list(true)
+            .boxed(),
+    }
+}
+
+#[cfg(all(test, feature = "otel"))]
+mod tests {
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+
+    use tracing_subscriber::layer::SubscriberExt;
+    use tracing_subscriber::Registry;
+
+    use super::*;
+
+    /// Accept one OTLP/HTTP request and return its request line and body.
+    fn collector(listener: TcpListener) -> (String, Vec<u8>) {
+        let (stream, _) = listener.accept().unwrap();
+        let mut reader = BufReader::new(stream);
+        let mut request_line = String::new();
+        reader.read_line(&mut request_line).unwrap();
+
+        let mut len = 0;
+        loop {
+            let mut header = String::new();
+            reader.read_line(&mut header).unwrap();
+            if header.trim().is_empty() {
+                break;
+            }
+            if let Some((name, value)) = header.split_once(':') {
+                if name.eq_ignore_ascii_case("content-length") {
+                    len = value.trim().parse().unwrap();
+                }
+            }
+        }
+        let mut body = vec![0; len];
+        reader.read_exact(&mut body).unwrap();
+        reader
+            .get_mut()
+            .write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\n\r\n")
+            .unwrap();
+
+        (request_line, body)
+    }
+
+    #[test]
+    fn exports_spans_to_collector() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = format!("http://{}/v1/traces", listener.local_addr().unwrap());
+        let collector = thread::spawn(move || collector(listener));
+
+        let (layer, provider) = otel_layer(endpoint, "memer-test".to_string()).unwrap();
+        tracing::subscriber::with_default(Registry::default().with(layer), || {
+            tracing::info_span!("command", command = "ping").in_scope(|| {});
+        });
+        provider.shutdown().unwrap();
+
+        let (request_line, body) = collector.join().unwrap();
+        assert!(request_line.starts_with("POST /v1/traces "));
+        let contains = |needle: &[u8]| body.windows(needle.len()).any(|w| w == needle);
+        assert!(contains(b"memer-test"));
+        assert!(contains(b"command"));
+        assert!(contains(b"ping"));
+    }
+}
@@ -3,6 +3,7 @@
 
 use std::env;
 use std::process::ExitCode;
+use std::sync::atomic::AtomicU64;
 use std::sync::Arc;
 use std::time::Instant;
 
@@ -13,26 +14,53 @@ use governor::clock::{Clock, QuantaUpkeepClock};
 use governor::state::keyed::DefaultKeyedStateStore;
 use governor::{Quota, RateLimiter};
 
+use poise::builtins::create_application_commands;
 use poise::serenity_prelude::*;
-use poise::{Framework, FrameworkOptions};
-use tokio::sync::mpsc;
-use tracing::{error, info, info_span, trace, Instrument};
-use tracing_subscriber::EnvFilter;
+use poise::{
+    ApplicationCommandOrAutocompleteInteraction, Framework, FrameworkError, FrameworkOptions,
+};
+use tokio::sync::oneshot;
+use tracing::{error, info, info_span, trace, warn, Instrument};
 
+mod activity;
+mod autopost;
 mod commands;
 mod data;
 mod db;
+mod digest;
+mod fiftyfifty;
+mod filter;
+mod hash;
+mod joke;
+mod logging;
+mod metrics;
+mod news;
+mod posts;
 mod result;
+mod search;
 mod serde;
 mod setup;
+mod shutdown;
+mod snapshot;
+mod validate;
 
-pub use data::Data;
+pub use data::{Data, Readiness, Subsystem};
 pub use result::ResultExt;
+pub use shutdown::Shutdown;
 
 pub type Context<'a> = poise::Context<'a, Data, Error>;
 
 #[tokio::main]
 async fn main() -> ExitCode {
+    // Held until exit so buffered log output is flushed
+    let _log_guard = match init() {
+        Ok(guard) => guard,
+        Err(e) => {
+            eprintln!("{e:#}");
+            return ExitCode::FAILURE;
+        }
+    };
+
     match run().await {
         Ok(_) => ExitCode::SUCCESS,
         Err(e) => {
@@ -42,36 +70,152 @@ async fn main() -> ExitCode {
     }
 }
 
-async fn run() -> Result<()> {
+/// Load environment variables and initialize logging.
+fn init() -> Result<logging::Guard> {
     #[cfg(feature = "dotenv")]
     dotenv::dotenv()?;
 
-    if env::var("MEMER_LOG").is_err() {
-        env::set_var("MEMER_LOG", "INFO");
-    }
+    logging::init()
+}
 
-    tracing_subscriber::fmt()
-        .with_target(false)
-        .with_env_filter(EnvFilter::from_env("MEMER_LOG"))
-        .init();
+async fn run() -> Result<()> {
     // TODO: https://doc.rust-lang.org/std/iter/trait.Iterator.html#method.intersperse
     trace!(command = %env::args().collect::<Vec<_>>().join(" "));
 
     let token = setup::token()?;
     let app_id = setup::app_id()?;
-    let (tx, rx) = mpsc::unbounded_channel::<()>();
-    let rx = Arc::new(Mutex::new(rx));
+    let snapshot_path = snapshot::path()?;
+    let snapshot_interval = snapshot::interval()?;
+    let stale_after = snapshot::stale_after()?;
+    let register_mode = setup::register_mode()?;
+    let activities = activity::templates()?;
+    let activity_interval = activity::interval()?;
+    let shutdown_timeout = shutdown::timeout()?;
+    let health_interval = db::health_interval()?;
+    let posts = Arc::new(DashMap::new());
+    let refreshed = Arc::new(DashMap::new());
+    let index = Arc::new(search::Index::default());
+    let summaries = Arc::new(news::Summaries::new()?);
+    let shutdown = Arc::new(Shutdown::default());
+    let (mongo, db) = db::client_and_db().await?;
+    let (writer, _) = db::Writer::spawn(db.clone());
+    // Background jobs that need both the serenity context and `Data` start once both exist
+    let (ctx_tx, ctx_rx) = oneshot::channel();
     let options: FrameworkOptions<Data, Error> = FrameworkOptions {
-        commands: vec![commands::admin::ping(), commands::admin::register()],
+        commands: commands::traced(vec![
+            commands::admin::ping(),
+            commands::admin::register(),
+            commands::admin::cache(),
+            commands::admin::refresh(),
+            commands::admin::reload(),
+            commands::admin::guilds(),
+            commands::admin::leave(),
+            commands::admin::activity(),
+            commands::admin::maintenance(),
+            commands::admin::killswitch(),
+            commands::autopost::autopost(),
+            commands::digest::digest(),
+            commands::browse::browse(),
+            commands::search::search(),
+            commands::filters::filters(),
+            commands::media::media(),
+        ]),
+        pre_command: |ctx| {
+            Box::pin(async move {
+                // The guard is dropped with the invocation data, once the command is handled
+                let guard = ctx.data().shutdown.command_started();
+                ctx.set_invocation_data(guard).await;
+            })
+        },
+        post_command: |ctx| Box::pin(command_finished(ctx, true)),
+        on_error: |error| {
+            Box::pin(async move {
+                // Commands that fail after `pre_command` don't reach `post_command`
+                match error {
+                    FrameworkError::Command { ctx, .. }
+                    | FrameworkError::ArgumentParse { ctx, .. } => {
+                        command_finished(ctx, false).await;
+                    }
+                    FrameworkError::CommandStructureMismatch { ctx, .. } => {
+                        command_finished(poise::Context::Application(ctx), false).await;
+                    }
+                    _ => (),
+                }
+
+                poise::builtins::on_error(error).await.or_trace();
+            })
+        },
+        listener: |ctx, event, _framework, _data| {
+            let span = info_span!("gateway_event", event = event.name());
+            Box::pin(
+                async move {
+                    // Buttons that outlive the command that sent them
+                    if let poise::Event::InteractionCreate {
+                        interaction: Interaction::MessageComponent(interaction),
+                    } = event
+                    {
+                        if interaction.data.custom_id == fiftyfifty::REVEAL_ID {
+                            if let Err(e) = fiftyfifty::reveal(ctx, interaction).await {
+                                warn!("failed to reveal post: {e:#}");
+                            }
+                        }
+                    }
+                    Ok(())
+                }
+                .instrument(span),
+            )
+        },
         command_check: Some(|ctx| {
             let data = ctx.data();
             let gov = data.governor.clone();
 
             Box::pin(async move {
+                // Checks also run for autocomplete, which can't be replied to and shouldn't use up
+                // the rate limit on every keystroke
+                if let poise::Context::Application(ctx) = ctx {
+                    if matches!(
+                        ctx.interaction,
+                        ApplicationCommandOrAutocompleteInteraction::Autocomplete(_)
+                    ) {
+                        return Ok(true);
+                    }
+                }
+
+                if data.shutdown.is_stopping() {
+                    ctx.say("The bot is restarting, try again in a moment!")
+                        .await
+                        .or_trace();
+                    return Ok(false);
+                }
+
+                // Bans, filters, the blacklist and maintenance mode aren't known until the database
+                // is loaded
+                let is_owner = ctx.framework().options().owners.contains(&ctx.author().id);
+                if !is_owner {
+                    if let Err(e) = data.require_database() {
+                        ctx.say(e.to_string()).await.or_trace();
+                        return Ok(false);
+                    }
+                }
+
+                let maintenance = {
+                    let settings = data.settings.read().await;
+                    settings
+                        .maintenance
+                        .then(|| settings.maintenance_message().to_string())
+                };
+                if let Some(message) = maintenance {
+                    if !is_owner {
+                        ctx.say(message).await.or_trace();
+                        return Ok(false);
+                    }
+                }
+
                 // Check the rate limiter before every command is executed
                 match gov.check_key(&ctx.channel_id()) {
                     Ok(_) => Ok(true),
                     Err(not_until) => {
+                        metrics::rate_limited();
                         ctx.say(format!(
                             "This channel is sending too many requests! Try again in {}",
                             humantime::format_duration(not_until.wait_time_from(data.clock.now())),
@@ -89,84 +233,283 @@ async fn run() -> Result<()> {
     let framework = Framework::build()
         .token(token)
         .client_settings(move |client| client.application_id(app_id))
-        .intents(GatewayIntents::GUILD_MESSAGES)
+        .intents(GatewayIntents::GUILDS | GatewayIntents::GUILD_MESSAGES)
         .options(options)
-        .user_data_setup(|ctx, ready, framework| {
-            Box::pin(
-                async move {
-                    info!("starting...");
-                    let timer = Instant::now();
-
-                    let Ready { user, guilds, .. } = ready;
-                    let bot_tag = user.tag();
-
-                    info!("logged in as {} on {} servers", bot_tag, guilds.len());
-
-                    setup::invite_url(ctx, ready).await;
-                    setup::set_activity(ctx).await;
-                    setup::register_commands(ctx, framework, guilds).await;
-
-                    // Unwrap: any invalid value will cause an error to propagate instead of panic
-                    data::SUBS.set(setup::subs_from_file()?).unwrap();
-                    let (mongo, db) = db::client_and_db().await?;
-                    let channels = db::all_channels(&db).await?;
-
-                    let clock = QuantaUpkeepClock::from_interval(std::time::Duration::from_secs(1))
-                        .map_err(|e| anyhow!("failed to create rate limiter clock: {e}"))?;
-                    // TODO: `quanta::Error` does not implement `std::error::Error`
-                    // https://github.com/metrics-rs/quanta/pull/68
-
-                    let data = Data {
-                        bot_id: user.id.0,
-                        bot_name: user.name.clone(),
-                        bot_tag,
-
-                        mongo,
-                        db,
-
-                        cache_time: Utc::now() + Duration::hours(1),
-                        blacklist_time: Utc::now() + Duration::hours(3),
-
-                        posts: setup::all_hot_posts().await,
-
-                        channels,
-                        blacklist: Arc::new(DashMap::new()),
-                        last_post: Arc::new(DashMap::new()),
-
-                        governor: Arc::new(RateLimiter::new(
-                            Quota::per_minute(
-                                // Unwrap: 10_u32 is a valid NonZeroU32
-                                10.try_into().unwrap(),
-                            ),
-                            DefaultKeyedStateStore::default(),
-                            &clock,
-                        )),
-                        clock,
-                    };
-
-                    info!("done in {}", humantime::format_duration(timer.elapsed()));
-                    Ok(data)
-                }
-                .instrument(info_span!("setup")),
-            )
+        .user_data_setup({
+            let snapshot_path = snapshot_path.clone();
+            let posts = posts.clone();
+            let refreshed = refreshed.clone();
+            let index = index.clone();
+            let shutdown = shutdown.clone();
+            let mongo = mongo.clone();
+            let db = db.clone();
+            let writer = writer.clone();
+
+            move |ctx, ready, framework| {
+                Box::pin(
+                    async move {
+                        info!("starting...");
+                        let timer = Instant::now();
+
+                        let Ready { user, guilds, .. } = ready;
+                        let bot_tag = user.tag();
+
+                        info!("logged in as {} on {} servers", bot_tag, guilds.len());
+
+                        setup::invite_url(ctx, ready).await;
+                        let _ = ctx_tx.send(ctx.clone());
+
+                        let settings = Arc::new(RwLock::new(db::Settings::default()));
+                        let memes_served = Arc::new(AtomicU64::new(0));
+                        let presence = Arc::new(activity::Presence::new(
+                            activities,
+                            user.name.clone(),
+                            memes_served.clone(),
+                            settings.clone(),
+                        ));
+                        tokio::spawn(presence.clone().rotate(ctx.clone(), activity_interval));
+
+                        data::set_subs(setup::subs_from_file()?);
+
+                        // Everything below is started in the background so commands can be handled
+                        // right away, and posts are fetched on demand until they're ready
+                        let readiness = Arc::new(Readiness::default());
+
+                        tokio::spawn({
+                            let readiness = readiness.clone();
+                            let commands =
+                                create_application_commands(&framework.options().commands);
+                            let guilds = guilds.clone();
+                            let ctx = ctx.clone();
+
+                            async move {
+                                setup::register_commands(&ctx, register_mode, commands, &guilds)
+                                    .await;
+                                readiness.set(Subsystem::Commands);
+                            }
+                        });
+
+                        // Serve posts from the snapshot while they are refreshed in the background
+                        if let Err(e) = snapshot::load(
+                            &snapshot_path,
+                            stale_after,
+                            &posts,
+                            &refreshed,
+                            &index,
+                            &summaries,
+                        ) {
+                            warn!("failed to load snapshot: {e:#}");
+                        }
+                        tokio::spawn({
+                            let readiness = readiness.clone();
+                            let posts = posts.clone();
+                            let refreshed = refreshed.clone();
+                            let index = index.clone();
+                            let summaries = summaries.clone();
+
+                            async move {
+                                setup::all_hot_posts(posts, refreshed, index, summaries).await;
+                                readiness.set(Subsystem::Posts);
+                            }
+                        });
+                        tokio::spawn(snapshot::periodic(
+                            snapshot_path,
+                            snapshot_interval,
+                            posts.clone(),
+                            refreshed.clone(),
+                        ));
+
+                        let channels = Arc::new(DashMap::new());
+                        let blacklist = Arc::new(DashMap::new());
+                        let last_post = Arc::new(DashMap::new());
+                        let bans = Arc::new(DashMap::new());
+                        let autoposts = Arc::new(DashMap::new());
+                        let digests = Arc::new(DashMap::new());
+                        let media = Arc::new(DashMap::new());
+                        let filters = Arc::new(DashMap::new());
+
+                        tokio::spawn({
+                            let readiness = readiness.clone();
+                            let db = db.clone();
+                            let channels = channels.clone();
+                            let blacklist = blacklist.clone();
+                            let last_post = last_post.clone();
+                            let bans = bans.clone();
+                            let autoposts = autoposts.clone();
+                            let digests = digests.clone();
+                            let media = media.clone();
+                            let filters = filters.clone();
+                            let settings = settings.clone();
+                            let presence = presence.clone();
+                            let ctx = ctx.clone();
+
+                            async move {
+                                setup::database(
+                                    &db, &channels, &blacklist, &last_post, &bans, &autoposts,
+                                    &digests, &media, &filters, &settings,
+                                )
+                                .await;
+                                if settings.read().await.maintenance {
+                                    warn!("maintenance mode is enabled");
+                                    presence.update(&ctx).await;
+                                }
+                                readiness.set(Subsystem::Database);
+                                db::health_probe(db, health_interval).await;
+                            }
+                        });
+
+                        if let Some(addr) = metrics::addr()? {
+                            tokio::spawn(metrics::serve(
+                                addr,
+                                metrics::Sources {
+                                    posts: posts.clone(),
+                                    blacklist: blacklist.clone(),
+                                    shard_manager: framework.shard_manager(),
+                                },
+                            ));
+                        }
+
+                        let clock =
+                            QuantaUpkeepClock::from_interval(std::time::Duration::from_secs(1))
+                                .map_err(|e| anyhow!("failed to create rate limiter clock: {e}"))?;
+                        // TODO: `quanta::Error` does not implement `std::error::Error`
+                        // https://github.com/metrics-rs/quanta/pull/68
+
+                        let data = Data {
+                            bot_id: user.id.0,
+                            bot_name: user.name.clone(),
+                            bot_tag,
+
+                            mongo,
+                            db,
+
+                            cache_time: Utc::now() + Duration::hours(1),
+                            blacklist_time: Utc::now() + Duration::hours(3),
+
+                            posts,
+                            index,
+                            summaries,
+                            validator: validate::Validator::new()?,
+                            hashes: hash::Hashes::new()?,
+                            refreshed,
+                            stale_after,
+
+                            channels,
+                            blacklist,
+                            last_post,
+                            bans,
+                            autoposts,
+                            digests,
+                            media,
+                            filters,
+                            writer,
+
+                            settings,
+                            presence,
+                            memes_served,
+
+                            ready: readiness,
+                            shutdown,
+
+                            governor: Arc::new(RateLimiter::new(
+                                Quota::per_minute(
+                                    // Unwrap: 10_u32 is a valid NonZeroU32
+                                    10.try_into().unwrap(),
+                                ),
+                                DefaultKeyedStateStore::default(),
+                                &clock,
+                            )),
+                            clock,
+                        };
+
+                        info!("done in {}", humantime::format_duration(timer.elapsed()));
+                        Ok(data)
+                    }
+                    .instrument(info_span!("setup")),
+                )
+            }
         })
         .build()
         .await?;
 
     let shard_mgr = framework.shard_manager();
 
-    tokio::spawn(async move {
-        match tokio::signal::ctrl_c().await {
-            Ok(_) => shard_mgr.lock().await.shutdown_all().await,
-            Err(e) => error!("failed to listen for ctrl-c signal: {e}"),
+    tokio::spawn({
+        let framework = framework.clone();
+
+        async move {
+            if let Ok(ctx) = ctx_rx.await {
+                let data = framework.user_data().await;
+
+                tokio::join!(
+                    autopost::run(ctx.clone(), data),
+                    digest::run(ctx, data),
+                    hash::run(data),
+                );
+            }
+        }
+    });
+
+    tokio::spawn({
+        let shutdown = shutdown.clone();
+
+        async move {
+            match shutdown::signal().await {
+                Ok(signal) => info!("received {signal}, shutting down..."),
+                Err(e) => {
+                    error!("failed to listen for shutdown signals: {e}");
+                    return;
+                }
+            }
+
+            // Stop accepting commands and let the ones being handled finish
+            shutdown.stop(shutdown_timeout);
+            // Unwrap: the deadline was just set
+            let deadline = shutdown.deadline().unwrap();
+
+            if tokio::time::timeout_at(deadline, shutdown.drained())
+                .await
+                .is_err()
+            {
+                warn!("shutdown deadline exceeded while waiting for commands to finish");
+            }
+
+            shard_mgr.lock().await.shutdown_all().await;
         }
     });
 
     info!("ready");
-    framework.start_autosharded().await?;
+    let res = framework.start_autosharded().await;
+
+    // Flush state that would otherwise be lost
+    let deadline = shutdown
+        .deadline()
+        .unwrap_or_else(|| tokio::time::Instant::now() + shutdown_timeout);
+    let flush = async {
+        writer.flush().await;
+
+        if !posts.is_empty() {
+            if let Err(e) = snapshot::save(&snapshot_path, &posts, &refreshed) {
+                warn!("failed to save snapshot: {e:#}");
+            }
+        }
 
-    drop(tx);
-    let _ = rx.lock().await.recv().await;
+        mongo.shutdown().await;
+    };
+
+    match tokio::time::timeout_at(deadline, flush).await {
+        Ok(_) => info!("shutdown complete"),
+        Err(_) => warn!("shutdown deadline exceeded, some state may not have been saved"),
+    }
+
+    res.map_err(Into::into)
+}
 
-    Ok(())
+/// Record a finished command's metrics. Shutdown tracking ends when its `CommandGuard` is dropped.
+async fn command_finished(ctx: Context<'_>, ok: bool) {
+    let elapsed = ctx
+        .invocation_data::<shutdown::CommandGuard>()
+        .await
+        .map(|guard| guard.started.elapsed());
+    metrics::command(&ctx.command().qualified_name, ok, elapsed);
 }
@@ -0,0 +1,286 @@
+//! Prometheus metrics, enabled with the `metrics` feature. Without the feature, recording metrics
+//! does nothing.
+
+#[cfg(feature = "metrics")]
+pub use enabled::*;
+
+#[cfg(not(feature = "metrics"))]
+pub use disabled::*;
+
+#[cfg(feature = "metrics")]
+mod enabled {
+    use std::convert::Infallible;
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use anyhow::{Context, Result};
+    use dashmap::DashMap;
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{header, Body, Request, Response, Server};
+    use once_cell::sync::Lazy;
+    use poise::serenity_prelude::{ChannelId, Mutex, ShardManager};
+    use prometheus::{
+        register_gauge_vec, register_histogram_vec, register_int_counter, register_int_counter_vec,
+        register_int_gauge, register_int_gauge_vec, Encoder, GaugeVec, HistogramVec, IntCounter,
+        IntCounterVec, IntGauge, IntGaugeVec, TextEncoder,
+    };
+    use tracing::{error, info};
+
+    use crate::data::{Blacklisted, QuickPost};
+    use crate::setup;
+
+    // Unwraps: metric names and labels are valid, and each metric is only registered once
+
+    static COMMANDS: Lazy<IntCounterVec> = Lazy::new(|| {
+        register_int_counter_vec!(
+            "memer_commands_total",
+            "Commands handled, by command and outcome.",
+            &["command", "outcome"]
+        )
+        .unwrap()
+    });
+    static COMMAND_LATENCY: Lazy<HistogramVec> = Lazy::new(|| {
+        register_histogram_vec!(
+            "memer_command_duration_seconds",
+            "Time taken to handle commands, by command.",
+            &["command"]
+        )
+        .unwrap()
+    });
+    static RATE_LIMITED: Lazy<IntCounter> = Lazy::new(|| {
+        register_int_counter!(
+            "memer_rate_limited_total",
+            "Commands rejected by the rate limiter."
+        )
+        .unwrap()
+    });
+    static REDDIT_FETCHES: Lazy<IntCounterVec> = Lazy::new(|| {
+        register_int_counter_vec!(
+            "memer_reddit_fetches_total",
+            "Reddit post fetches, by subreddit and outcome.",
+            &["subreddit", "outcome"]
+        )
+        .unwrap()
+    });
+    static MONGO_LATENCY: Lazy<HistogramVec> = Lazy::new(|| {
+        register_histogram_vec!(
+            "memer_mongo_operation_duration_seconds",
+            "Time taken by database operations, by operation.",
+            &["operation"]
+        )
+        .unwrap()
+    });
+    static DROPPED_WRITES: Lazy<IntCounter> = Lazy::new(|| {
+        register_int_counter!(
+            "memer_dropped_writes_total",
+            "Queued database writes dropped after repeated failures."
+        )
+        .unwrap()
+    });
+    static CACHED_POSTS: Lazy<IntGaugeVec> = Lazy::new(|| {
+        register_int_gauge_vec!(
+            "memer_cached_posts",
+            "Posts in the cache, by subreddit.",
+            &["subreddit"]
+        )
+        .unwrap()
+    });
+    static BLACKLISTED_POSTS: Lazy<IntGauge> = Lazy::new(|| {
+        register_int_gauge!(
+            "memer_blacklisted_posts",
+            "Posts blacklisted across all channels."
+        )
+        .unwrap()
+    });
+    static BLACKLISTED_CHANNELS: Lazy<IntGauge> = Lazy::new(|| {
+        register_int_gauge!(
+            "memer_blacklisted_channels",
+            "Channels with blacklisted posts."
+        )
+        .unwrap()
+    });
+    static SHARD_LATENCY: Lazy<GaugeVec> = Lazy::new(|| {
+        register_gauge_vec!(
+            "memer_shard_latency_seconds",
+            "Gateway heartbeat latency, by shard.",
+            &["shard"]
+        )
+        .unwrap()
+    });
+
+    /// Record a handled command.
+    pub fn command(name: &str, ok: bool, elapsed: Option<Duration>) {
+        COMMANDS
+            .with_label_values(&[name, if ok { "ok" } else { "error" }])
+            .inc();
+
+        if let Some(elapsed) = elapsed {
+            COMMAND_LATENCY
+                .with_label_values(&[name])
+                .observe(elapsed.as_secs_f64());
+        }
+    }
+
+    /// Record a command rejected by the rate limiter.
+    pub fn rate_limited() {
+        RATE_LIMITED.inc();
+    }
+
+    /// Record a reddit fetch.
+    pub fn reddit_fetch(sub: &str, ok: bool) {
+        REDDIT_FETCHES
+            .with_label_values(&[sub, if ok { "ok" } else { "error" }])
+            .inc();
+    }
+
+    /// Record a database operation.
+    pub fn mongo(operation: &str, elapsed: Duration) {
+        MONGO_LATENCY
+            .with_label_values(&[operation])
+            .observe(elapsed.as_secs_f64());
+    }
+
+    /// Record queued database writes that were dropped instead of retried.
+    pub fn dropped_writes(count: usize) {
+        DROPPED_WRITES.inc_by(count.try_into().unwrap_or(u64::MAX));
+    }
+
+    /// Runtime data that gauges are read from when metrics are scraped.
+    #[derive(Clone)]
+    pub struct Sources {
+        pub posts: Arc<DashMap<String, Vec<QuickPost>>>,
+        pub blacklist: Arc<DashMap<ChannelId, Vec<Blacklisted>>>,
+        pub shard_manager: Arc<Mutex<ShardManager>>,
+    }
+
+    impl Sources {
+        /// Update gauges from runtime data.
+        async fn update(&self) {
+            // Subreddits removed by a reload would otherwise keep reporting their last count
+            CACHED_POSTS.reset();
+            for entry in self.posts.iter() {
+                CACHED_POSTS
+                    .with_label_values(&[entry.key()])
+                    .set(entry.value().len().try_into().unwrap_or(i64::MAX));
+            }
+
+            let blacklisted = self
+                .blacklist
+                .iter()
+                .map(|entry| entry.len())
+                .sum::<usize>();
+            BLACKLISTED_POSTS.set(blacklisted.try_into().unwrap_or(i64::MAX));
+            BLACKLISTED_CHANNELS.set(self.blacklist.len().try_into().unwrap_or(i64::MAX));
+
+            let shard_manager = self.shard_manager.lock().await;
+            for (id, runner) in shard_manager.runners.lock().await.iter() {
+                if let Some(latency) = runner.latency {
+                    SHARD_LATENCY
+                        .with_label_values(&[&id.0.to_string()])
+                        .set(latency.as_secs_f64());
+                }
+            }
+        }
+    }
+
+    /// Get the metrics endpoint address, if metrics are enabled.
+    pub fn addr() -> Result<Option<SocketAddr>> {
+        Ok(Some(
+            setup::env_parse("MEMER_METRICS_ADDR")?
+                .unwrap_or_else(|| SocketAddr::from(([0, 0, 0, 0], 9090))),
+        ))
+    }
+
+    /// Serve metrics over HTTP.
+    #[tracing::instrument(skip(sources))]
+    pub async fn serve(addr: SocketAddr, sources: Sources) {
+        let make_service = make_service_fn(move |_| {
+            let sources = sources.clone();
+
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req| {
+                    let sources = sources.clone();
+                    async move { Ok::<_, Infallible>(handle(req, sources).await) }
+                }))
+            }
+        });
+
+        let server = match Server::try_bind(&addr) {
+            Ok(builder) => builder.serve(make_service),
+            Err(e) => {
+                error!("failed to bind metrics endpoint: {e}");
+                return;
+            }
+        };
+
+        info!("serving metrics on http://{addr}/metrics");
+        if let Err(e) = server.await {
+            error!("metrics endpoint failed: {e}");
+        }
+    }
+
+    async fn handle(req: Request<Body>, sources: Sources) -> Response<Body> {
+        if req.uri().path() != "/metrics" {
+            return Response::builder()
+                .status(404)
+                .body(Body::empty())
+                .unwrap_or_default();
+        }
+
+        sources.update().await;
+
+        let encoder = TextEncoder::new();
+        let mut buf = Vec::new();
+
+        match encoder
+            .encode(&prometheus::gather(), &mut buf)
+            .context("failed to encode metrics")
+        {
+            Ok(_) => Response::builder()
+                .header(header::CONTENT_TYPE, encoder.format_type())
+                .body(Body::from(buf))
+                .unwrap_or_default(),
+            Err(e) => {
+                error!("{e:#}");
+                Response::builder()
+                    .status(500)
+                    .body(Body::empty())
+                    .unwrap_or_default()
+            }
+        }
+    }
+}
+
+#[cfg(not(feature = "metrics"))]
+mod disabled {
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use anyhow::Result;
+    use dashmap::DashMap;
+    use poise::serenity_prelude::{ChannelId, Mutex, ShardManager};
+
+    use crate::data::{Blacklisted, QuickPost};
+
+    pub const fn command(_name: &str, _ok: bool, _elapsed: Option<Duration>) {}
+    pub const fn rate_limited() {}
+    pub const fn reddit_fetch(_sub: &str, _ok: bool) {}
+    pub const fn mongo(_operation: &str, _elapsed: Duration) {}
+    pub const fn dropped_writes(_count: usize) {}
+
+    #[allow(dead_code)] // Only read when metrics are enabled
+    #[derive(Clone)]
+    pub struct Sources {
+        pub posts: Arc<DashMap<String, Vec<QuickPost>>>,
+        pub blacklist: Arc<DashMap<ChannelId, Vec<Blacklisted>>>,
+        pub shard_manager: Arc<Mutex<ShardManager>>,
+    }
+
+    pub const fn addr() -> Result<Option<SocketAddr>> {
+        Ok(None)
+    }
+
+    pub async fn serve(_addr: SocketAddr, _sources: Sources) {}
+}
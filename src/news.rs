@@ -0,0 +1,316 @@
+//! News group posts, rendered as article cards.
+
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Result};
+use dashmap::mapref::entry::Entry;
+use dashmap::DashMap;
+use once_cell::sync::Lazy;
+use poise::futures_util::{stream, StreamExt};
+use poise::serenity_prelude::CreateEmbed;
+use regex::Regex;
+use reqwest::redirect::Policy;
+use tracing::debug;
+
+use crate::data::{self, QuickPost};
+use crate::{posts, setup};
+
+/// Timeout of requests for article pages.
+const TIMEOUT: Duration = Duration::from_secs(5);
+/// Maximum number of redirects followed when fetching an article page.
+const MAX_REDIRECTS: usize = 5;
+/// Maximum number of bytes of an article page read when looking for its summary.
+const MAX_PAGE_LEN: usize = 512 * 1024;
+/// Maximum length of a summary.
+const SUMMARY_LEN: usize = 300;
+/// Number of article pages fetched at a time.
+const CONCURRENCY: usize = 4;
+/// How long a summary is kept before it's fetched again.
+const TTL: Duration = Duration::from_secs(24 * 60 * 60);
+/// How long until an article without a summary, e.g. because fetching it failed, is fetched again.
+const RETRY_AFTER: Duration = Duration::from_secs(30 * 60);
+
+/// An HTML meta tag.
+static META: Lazy<Regex> = Lazy::new(|| Regex::new(r"(?i)<meta\s[^>]*>").unwrap());
+/// An HTML attribute with a quoted value.
+static ATTR: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r#"([\w:-]+)\s*=\s*(?:"([^"]*)"|'([^']*)')"#).unwrap());
+
+/// Summaries of news articles, fetched in the background when news posts are cached.
+#[derive(Debug)]
+pub struct Summaries {
+    /// Client for fetching article pages, if summaries are enabled.
+    client: Option<reqwest::Client>,
+    /// Map of article URLs and their summaries.
+    summaries: DashMap<String, Summary>,
+}
+
+/// An article's summary.
+#[derive(Debug)]
+struct Summary {
+    /// Subreddit the article was posted in.
+    sub: String,
+    /// `None` while the article is being fetched, or if it has no summary.
+    text: Option<String>,
+    /// When the article was last fetched.
+    fetched: Instant,
+}
+
+impl Summary {
+    /// Whether the article should be fetched again.
+    fn is_expired(&self) -> bool {
+        let ttl = if self.text.is_some() {
+            TTL
+        } else {
+            RETRY_AFTER
+        };
+        self.fetched.elapsed() > ttl
+    }
+}
+
+impl Summaries {
+    /// Enable article summaries if `MEMER_NEWS_SUMMARIES` is set.
+    pub fn new() -> Result<Self> {
+        let client = if setup::env_parse::<bool>("MEMER_NEWS_SUMMARIES")?.unwrap_or_default() {
+            Some(
+                reqwest::Client::builder()
+                    .timeout(TIMEOUT)
+                    .redirect(Policy::limited(MAX_REDIRECTS))
+                    .user_agent(concat!("memer/", env!("CARGO_PKG_VERSION")))
+                    .build()?,
+            )
+        } else {
+            None
+        };
+
+        Ok(Self {
+            client,
+            summaries: DashMap::new(),
+        })
+    }
+
+    /// Fetch the summaries of a news subreddit's articles in the background. Summaries of its
+    /// articles that are no longer cached, and expired summaries, are forgotten.
+    pub fn prefetch(self: &Arc<Self>, sub: &str, posts: &[QuickPost]) {
+        let Some(client) = self.client.clone() else {
+            return;
+        };
+        if !data::in_group(data::NEWS_GROUP, sub) {
+            return;
+        }
+
+        let urls = posts
+            .iter()
+            .map(|post| post.content.as_str())
+            .collect::<HashSet<_>>();
+        self.summaries.retain(|url, summary| {
+            (summary.sub != sub || urls.contains(url.as_str())) && !summary.is_expired()
+        });
+
+        // Claim the articles that haven't been fetched, so they're only fetched once
+        let missing = urls
+            .into_iter()
+            .filter(|url| match self.summaries.entry((*url).to_string()) {
+                Entry::Occupied(_) => false,
+                Entry::Vacant(entry) => {
+                    entry.insert(Summary {
+                        sub: sub.to_string(),
+                        text: None,
+                        fetched: Instant::now(),
+                    });
+                    true
+                }
+            })
+            .map(str::to_string)
+            .collect::<Vec<_>>();
+
+        let summaries = self.clone();
+        tokio::spawn(
+            stream::iter(missing).for_each_concurrent(CONCURRENCY, move |url| {
+                let client = client.clone();
+                let summaries = summaries.clone();
+
+                async move {
+                    let text = match summary(&client, &url).await {
+                        Ok(text) => text,
+                        Err(e) => {
+                            debug!("failed to get summary of {url}: {e:#}");
+                            None
+                        }
+                    };
+                    if let Some(mut entry) = summaries.summaries.get_mut(&url) {
+                        entry.text = text;
+                        entry.fetched = Instant::now();
+                    }
+                }
+            }),
+        );
+    }
+
+    /// Get an article's summary, if it has been fetched.
+    fn get(&self, url: &str) -> Option<String> {
+        self.summaries
+            .get(url)
+            .and_then(|summary| summary.text.clone())
+    }
+}
+
+/// Whether a post is from a subreddit in the news group.
+pub fn is_news(post: &QuickPost) -> bool {
+    data::in_group(data::NEWS_GROUP, &post.sub)
+}
+
+/// Whether a domain is a listed domain or one of its subdomains.
+pub fn domain_matches(domain: &str, listed: &str) -> bool {
+    domain.eq_ignore_ascii_case(listed)
+        || domain
+            .to_lowercase()
+            .ends_with(&format!(".{}", listed.to_lowercase()))
+}
+
+/// Get an article's summary from its OpenGraph description, or its meta description.
+async fn summary(client: &reqwest::Client, url: &str) -> Result<Option<String>> {
+    let mut res = client.get(url).send().await?.error_for_status()?;
+    let is_html = res
+        .headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+        .is_some_and(|content_type| content_type.contains("html"));
+    if !is_html {
+        bail!("not an HTML page");
+    }
+
+    let mut page = Vec::new();
+    while let Some(chunk) = res.chunk().await? {
+        page.extend_from_slice(&chunk);
+        if page.len() >= MAX_PAGE_LEN {
+            break;
+        }
+    }
+
+    Ok(description(&String::from_utf8_lossy(&page)))
+}
+
+/// Get a page's OpenGraph description, or its meta description.
+fn description(page: &str) -> Option<String> {
+    let mut description = None;
+    for meta in META.find_iter(page) {
+        let mut key = None;
+        let mut content = None;
+        for attr in ATTR.captures_iter(meta.as_str()) {
+            let value = attr
+                .get(2)
+                .or_else(|| attr.get(3))
+                .map(|value| value.as_str());
+            match attr[1].to_lowercase().as_str() {
+                "property" | "name" => key = value.map(str::to_lowercase),
+                "content" => content = value,
+                _ => (),
+            }
+        }
+
+        match (key.as_deref(), content) {
+            (Some("og:description"), Some(content)) => {
+                description = Some(clean(content));
+                break;
+            }
+            (Some("description"), Some(content)) => description = Some(clean(content)),
+            _ => (),
+        }
+    }
+
+    description.filter(|description| !description.is_empty())
+}
+
+/// Decode common HTML entities in a summary, and shorten it.
+fn clean(s: &str) -> String {
+    let s = s
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&#x27;", "'")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&amp;", "&");
+
+    posts::truncate(s.trim(), SUMMARY_LEN)
+}
+
+/// Render a post as a card with the article's domain, title and summary, and the post's age.
+pub fn embed<'a>(
+    summaries: &Summaries,
+    post: &QuickPost,
+    e: &'a mut CreateEmbed,
+) -> &'a mut CreateEmbed {
+    let mut description = summaries
+        .get(&post.content)
+        .map(|summary| format!("{summary}\n\n"))
+        .unwrap_or_default();
+    if post.created > 0 {
+        description.push_str(&format!("Posted <t:{}:R> • ", post.created));
+    }
+    description.push_str(&format!("[Comments]({})", posts::url(post)));
+
+    e.author(|a| a.name(&post.domain))
+        .title(posts::truncate(&post.title, posts::TITLE_LEN))
+        .url(&post.content)
+        .description(description)
+        .footer(|f| f.text(posts::footer(post)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_subdomains_only() {
+        assert!(domain_matches("bbc.com", "bbc.com"));
+        assert!(domain_matches("news.BBC.com", "bbc.com"));
+        assert!(domain_matches("bbc.com", "BBC.COM"));
+        assert!(!domain_matches("evilbbc.com", "bbc.com"));
+        assert!(!domain_matches("bbc.com.evil.net", "bbc.com"));
+        assert!(!domain_matches("bbc.co.uk", "bbc.com"));
+    }
+
+    #[test]
+    fn prefers_opengraph_description() {
+        let page = r#"<html><head>
+            <meta charset="utf-8">
+            <meta name="description" content="Meta description">
+            <META content='OpenGraph &quot;description&quot;' property="OG:Description" />
+            </head></html>"#;
+
+        assert_eq!(
+            description(page).as_deref(),
+            Some(r#"OpenGraph "description""#)
+        );
+    }
+
+    #[test]
+    fn falls_back_to_meta_description() {
+        let page = r#"<meta property="og:title" content="Title">
+            <meta name = 'description' content = "  Tom &amp; Jerry  ">"#;
+
+        assert_eq!(description(page).as_deref(), Some("Tom & Jerry"));
+        assert_eq!(
+            description(r#"<meta name="description" content=" ">"#),
+            None
+        );
+        assert_eq!(description("<p>No meta tags</p>"), None);
+    }
+
+    #[test]
+    fn cleans_summaries() {
+        assert_eq!(
+            clean(" &lt;b&gt;It&#39;s &quot;here&quot;&#x27; &amp;amp; "),
+            r#"<b>It's "here"' &amp;"#
+        );
+
+        let long = "a".repeat(SUMMARY_LEN + 10);
+        let cleaned = clean(&long);
+        assert_eq!(cleaned.chars().count(), SUMMARY_LEN);
+        assert!(cleaned.ends_with('…'));
+        assert_eq!(clean(&"a".repeat(SUMMARY_LEN)), "a".repeat(SUMMARY_LEN));
+    }
+}
@@ -0,0 +1,283 @@
+//! Choosing posts to send to channels, and rendering them.
+
+use std::collections::HashSet;
+
+use anyhow::{anyhow, bail, Result};
+use poise::futures_util::future;
+use poise::serenity_prelude::{
+    CacheHttp, Channel, ChannelId, CreateComponents, CreateEmbed, GuildId,
+};
+use rand::Rng;
+
+use crate::data::{self, Kind, QuickPost};
+use crate::{fiftyfifty, joke, news, Data};
+
+/// Maximum length of an embed title.
+pub const TITLE_LEN: usize = 256;
+/// Maximum length of an embed description.
+pub const DESCRIPTION_LEN: usize = 4096;
+/// Maximum number of posts validated when choosing a random post.
+const VALIDATE_ATTEMPTS: usize = 5;
+
+/// A channel that posts are sent to.
+#[derive(Debug, Clone, Copy)]
+pub struct Target {
+    pub channel: ChannelId,
+    /// The channel's guild, whose post filters apply.
+    pub guild: Option<GuildId>,
+    /// Whether the channel allows NSFW posts.
+    pub nsfw: bool,
+    /// The kind of post to send, if limited.
+    pub kind: Option<Kind>,
+}
+
+impl Target {
+    /// Limit posts to a kind instead of the channel's default, if one is given.
+    pub fn with_kind(self, kind: Option<Kind>) -> Self {
+        Self {
+            kind: kind.or(self.kind),
+            ..self
+        }
+    }
+}
+
+/// Look up a channel that posts are sent to, and the kind of post it's limited to. Only guild
+/// channels can be marked NSFW.
+pub async fn target(cache_http: impl CacheHttp, data: &Data, channel: ChannelId) -> Result<Target> {
+    let kind = data.media.get(&channel).map(|kind| *kind);
+
+    Ok(match channel.to_channel(cache_http).await? {
+        Channel::Guild(guild_channel) => Target {
+            channel,
+            guild: Some(guild_channel.guild_id),
+            nsfw: guild_channel.is_nsfw(),
+            kind,
+        },
+        _ => Target {
+            channel,
+            guild: None,
+            nsfw: false,
+            kind,
+        },
+    })
+}
+
+/// Get the subreddits in a group, or a single subreddit from any group, that can be sent to a
+/// channel. The subreddits' groups must be enabled, and subreddits banned in the channel are left
+/// out.
+pub async fn subs(data: &Data, channel: ChannelId, name: &str) -> Result<Vec<String>> {
+    let (groups, subs) = match data::group(name) {
+        Some(subs) => (vec![name.to_string()], subs),
+        None => {
+            let name = name.trim_start_matches("r/");
+            let mut groups = Vec::new();
+            let mut found = None;
+
+            for group in data::SUBS.iter() {
+                if let Some(sub) = group.iter().find(|sub| sub.eq_ignore_ascii_case(name)) {
+                    groups.push(group.key().clone());
+                    found = Some(sub.clone());
+                }
+            }
+            let sub = found.ok_or_else(|| anyhow!("There is no {name} group or subreddit."))?;
+
+            (groups, vec![sub])
+        }
+    };
+
+    {
+        let settings = data.settings.read().await;
+        if let Some(group) = groups.iter().find(|group| settings.is_disabled(group)) {
+            bail!("The {group} group is disabled right now, try again later!");
+        }
+    }
+
+    let subs = match data.bans.get(&channel) {
+        Some(bans) => subs
+            .into_iter()
+            .filter(|sub| !bans.contains(&sub.to_lowercase()))
+            .collect::<Vec<_>>(),
+        None => subs,
+    };
+    if subs.is_empty() {
+        bail!("Every subreddit in {name} is banned in this channel.");
+    }
+
+    Ok(subs)
+}
+
+/// Get the cached posts from a subreddit group or subreddit that can be sent to a channel, by
+/// score. Posts from subreddits that can't be sent to the channel, NSFW posts in SFW channels,
+/// posts of other kinds than the target's, posts blacklisted in the channel, and posts blocked by
+/// the guild's filters are left out.
+pub async fn candidates(data: &Data, target: &Target, name: &str) -> Result<Vec<QuickPost>> {
+    let subs = subs(data, target.channel, name).await?;
+
+    // Only fail if no subreddit in the group could be loaded
+    let mut loaded = future::join_all(subs.iter().map(|sub| data.load_sub(sub))).await;
+    if loaded.iter().all(Result::is_err) {
+        loaded.swap_remove(0)?;
+    }
+
+    let posts = subs
+        .iter()
+        .filter_map(|sub| data.posts.get(sub))
+        .flat_map(|posts| posts.value().clone())
+        .collect::<Vec<_>>();
+    if !target.nsfw && !posts.is_empty() && posts.iter().all(|post| post.nsfw) {
+        bail!("{name} can only be used in NSFW channels.");
+    }
+
+    let mut posts = allowed(data, target, posts);
+    if posts.is_empty() {
+        match target.kind {
+            Some(kind) => {
+                bail!("There are no {name} {kind} posts left for this channel, try again later!")
+            }
+            None => bail!("There are no {name} posts left for this channel, try again later!"),
+        }
+    }
+
+    // Keep the highest scoring copy of posts crossposted between subreddits
+    posts.sort_unstable_by(|a, b| b.score.total_cmp(&a.score));
+    data::dedup(&mut posts);
+    Ok(posts)
+}
+
+/// Leave out NSFW posts in SFW channels, posts of other kinds than the target's, posts blacklisted
+/// in a channel or duplicates of them, and posts blocked by the channel's guild's filters. With the
+/// `image-hash` feature, images nearly identical to blacklisted ones are duplicates.
+pub fn allowed(data: &Data, target: &Target, posts: Vec<QuickPost>) -> Vec<QuickPost> {
+    let blacklist = data.blacklist.get(&target.channel);
+    let mut seen = blacklist
+        .iter()
+        .flat_map(|blacklist| blacklist.iter())
+        .filter(|post| !post.is_expired())
+        .map(|post| post.key.as_str())
+        .collect::<HashSet<_>>();
+    let last_post = data.last_post.get(&target.channel);
+    if let Some(last) = &last_post {
+        seen.insert(&last.key);
+    }
+    let seen_hashes = data.hashes.get_all(seen.iter().copied());
+    let filter = target.guild.and_then(|guild| data.filters.get(&guild));
+
+    posts
+        .into_iter()
+        .filter(|post| target.nsfw || !post.nsfw)
+        .filter(|post| target.kind.is_none_or(|kind| post.kind == kind))
+        .filter(|post| filter.as_ref().is_none_or(|filter| filter.allows(post)))
+        .filter(|post| !seen.contains(post.key.as_str()))
+        .filter(|post| !data.hashes.is_repost(&post.key, &seen_hashes))
+        .collect()
+}
+
+/// Choose a random post from a subreddit group or subreddit that can be sent to a channel. Posts
+/// that fail validation are dropped from the cache, and another is chosen.
+pub async fn random(data: &Data, target: &Target, name: &str) -> Result<QuickPost> {
+    let mut posts = candidates(data, target, name).await?;
+
+    for _ in 0..VALIDATE_ATTEMPTS {
+        let post = posts.swap_remove(rand::thread_rng().gen_range(0..posts.len()));
+        if validate(data, &post).await {
+            return Ok(post);
+        }
+
+        if posts.is_empty() {
+            break;
+        }
+    }
+
+    bail!("There are no {name} posts left for this channel, try again later!");
+}
+
+/// Whether a post can still be sent. Posts that fail validation are dropped from the cache.
+pub async fn validate(data: &Data, post: &QuickPost) -> bool {
+    let valid = data.validator.check(post).await;
+    if !valid {
+        data.remove_post(post);
+    }
+
+    valid
+}
+
+/// Record a post as sent to a channel, so it isn't sent there again while blacklisted.
+pub fn served(data: &Data, channel: ChannelId, post: &QuickPost) {
+    data.add_blacklist(channel, post.clone());
+    data.set_last_post(channel, post.clone());
+}
+
+/// Send a random post from a subreddit group or subreddit to a channel.
+#[tracing::instrument(skip(cache_http, data), fields(channel = %channel))]
+pub async fn send(
+    cache_http: impl CacheHttp,
+    data: &Data,
+    channel: ChannelId,
+    name: &str,
+) -> Result<QuickPost> {
+    let target = target(&cache_http, data, channel).await?;
+    let post = random(data, &target, name).await?;
+
+    channel
+        .send_message(cache_http.http(), |msg| {
+            msg.embed(|e| embed(data, &post, e))
+                .components(|c| components(&post, c))
+        })
+        .await?;
+    served(data, channel, &post);
+
+    Ok(post)
+}
+
+/// Render a post as an embed.
+pub fn embed<'a>(data: &Data, post: &QuickPost, e: &'a mut CreateEmbed) -> &'a mut CreateEmbed {
+    if fiftyfifty::is_fiftyfifty(post) {
+        return fiftyfifty::embed(post, e);
+    }
+    if joke::is_joke(post) {
+        return joke::embed(post, e);
+    }
+    if news::is_news(post) {
+        return news::embed(&data.summaries, post, e);
+    }
+
+    e.title(truncate(&post.title, TITLE_LEN))
+        .url(url(post))
+        .footer(|f| f.text(footer(post)));
+
+    if let Some(image) = post.image() {
+        e.image(image)
+    } else {
+        e.description(truncate(&post.content, DESCRIPTION_LEN))
+    }
+}
+
+/// Add a post's buttons, if it has any.
+pub fn components<'a>(post: &QuickPost, c: &'a mut CreateComponents) -> &'a mut CreateComponents {
+    if fiftyfifty::is_fiftyfifty(post) {
+        c.create_action_row(fiftyfifty::reveal_button);
+    }
+
+    c
+}
+
+/// The footer of a post's embed.
+pub fn footer(post: &QuickPost) -> String {
+    format!("r/{} • {} points", post.sub, post.score)
+}
+
+/// The URL of a post's comments.
+pub fn url(post: &QuickPost) -> String {
+    format!("https://www.reddit.com{}", post.permalink)
+}
+
+/// Truncate a string to at most `len` characters, ending with an ellipsis if it was cut.
+pub fn truncate(s: &str, len: usize) -> String {
+    if s.chars().count() <= len {
+        return s.to_string();
+    }
+
+    let mut truncated = s.chars().take(len - 1).collect::<String>();
+    truncated.push('…');
+    truncated
+}
@@ -0,0 +1,224 @@
+//! Keyword search over cached posts.
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+
+use dashmap::DashMap;
+
+use crate::data::{self, QuickPost};
+
+/// Weight of a query term found in a post's title, relative to one found in its self-text.
+const TITLE_WEIGHT: f64 = 2.0;
+/// Weight of a post's score in its rank, relative to search relevance.
+const SCORE_WEIGHT: f64 = 0.1;
+
+/// Inverted index of each subreddit's cached posts, rebuilt whenever a subreddit's posts change.
+/// Posts aren't copied into the index, they're looked up in the post cache by position.
+#[derive(Debug, Default)]
+pub struct Index(DashMap<String, SubIndex>);
+
+/// Inverted index of a subreddit's posts.
+#[derive(Debug, Default)]
+struct SubIndex {
+    /// Number of posts indexed.
+    len: usize,
+    /// Fingerprint of the posts indexed, to tell if the cache changed since.
+    fingerprint: u64,
+    /// Map of terms and the weight of each post they appear in, by position in the cache.
+    terms: HashMap<String, Vec<(usize, f64)>>,
+}
+
+impl Index {
+    /// Index a subreddit's posts, replacing any that were indexed before. Self-text is only
+    /// indexed for subreddits in the text group.
+    pub fn update(&self, sub: &str, posts: &[QuickPost]) {
+        let text = data::in_group(data::TEXT_GROUP, sub);
+        let mut terms = HashMap::<String, Vec<(usize, f64)>>::new();
+
+        for (i, post) in posts.iter().enumerate() {
+            let mut weights = HashMap::<String, f64>::new();
+            for term in tokenize(&post.title) {
+                *weights.entry(term).or_default() += TITLE_WEIGHT;
+            }
+            if text {
+                for term in tokenize(&post.content) {
+                    *weights.entry(term).or_default() += 1.0;
+                }
+            }
+
+            for (term, weight) in weights {
+                terms.entry(term).or_default().push((i, weight));
+            }
+        }
+
+        self.0.insert(
+            sub.to_string(),
+            SubIndex {
+                len: posts.len(),
+                fingerprint: fingerprint(posts),
+                terms,
+            },
+        );
+    }
+
+    /// Remove subreddits from the index.
+    pub fn retain(&self, f: impl Fn(&str) -> bool) {
+        self.0.retain(|sub, _| f(sub));
+    }
+
+    /// Find cached posts in `subs` matching any term in `query`, ranked by relevance and then
+    /// score. Terms that are rarer across the searched posts are more relevant.
+    pub fn search(
+        &self,
+        posts: &DashMap<String, Vec<QuickPost>>,
+        subs: &[String],
+        query: &str,
+    ) -> Vec<QuickPost> {
+        let query = tokenize(query).collect::<Vec<_>>();
+        // Relevance of matching posts by position, and the fingerprint of the posts indexed, by
+        // subreddit
+        let mut matched = HashMap::<&str, (u64, HashMap<usize, f64>)>::new();
+
+        // The index isn't locked while the cache is, since the cache is locked while indexing
+        {
+            let indexes = subs
+                .iter()
+                .filter_map(|sub| self.0.get(sub).map(|index| (sub.as_str(), index)))
+                .collect::<Vec<_>>();
+            let total = indexes.iter().map(|(_, index)| index.len).sum::<usize>() as f64;
+
+            for term in &query {
+                let matches = indexes
+                    .iter()
+                    .filter_map(|(_, index)| index.terms.get(term))
+                    .map(Vec::len)
+                    .sum::<usize>();
+                if matches == 0 {
+                    continue;
+                }
+                let idf = (total / matches as f64).ln() + 1.0;
+
+                for (sub, index) in &indexes {
+                    for &(i, weight) in index.terms.get(term).into_iter().flatten() {
+                        let (_, relevance) = matched
+                            .entry(sub)
+                            .or_insert_with(|| (index.fingerprint, HashMap::new()));
+                        *relevance.entry(i).or_default() += weight * idf;
+                    }
+                }
+            }
+        }
+
+        let mut ranked = Vec::new();
+        for (sub, (indexed, relevance)) in matched {
+            // Posts that changed since they were indexed are skipped until they're indexed again
+            let Some(cached) = posts
+                .get(sub)
+                .filter(|cached| fingerprint(cached) == indexed)
+            else {
+                continue;
+            };
+            for (i, relevance) in relevance {
+                let post = &cached[i];
+                let rank = SCORE_WEIGHT.mul_add(post.score.max(0.0).ln_1p(), relevance);
+                ranked.push((rank, post.clone()));
+            }
+        }
+        ranked.sort_unstable_by(|a, b| b.0.total_cmp(&a.0));
+
+        ranked.into_iter().map(|(_, post)| post).collect()
+    }
+}
+
+/// Hash the permalinks of a subreddit's posts in order, so posts that were replaced, removed or
+/// reordered are told apart from the ones indexed even if there are as many of them.
+fn fingerprint(posts: &[QuickPost]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    for post in posts {
+        post.permalink.hash(&mut hasher);
+    }
+    hasher.finish()
+}
+
+/// Split text into lowercase alphanumeric terms, ignoring single characters.
+fn tokenize(text: &str) -> impl Iterator<Item = String> + '_ {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|term| term.chars().count() > 1)
+        .map(str::to_lowercase)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn post(title: &str, score: f64) -> QuickPost {
+        QuickPost {
+            title: title.to_string(),
+            score,
+            content: String::new(),
+            nsfw: false,
+            permalink: format!("/r/test/comments/{}/", title.len()),
+            sub: "test".to_string(),
+            flair: None,
+            spoiler: false,
+            kind: data::Kind::Image,
+            domain: "i.redd.it".to_string(),
+            created: 0,
+            key: title.to_string(),
+        }
+    }
+
+    fn titles(posts: &[QuickPost]) -> Vec<&str> {
+        posts.iter().map(|post| post.title.as_str()).collect()
+    }
+
+    #[test]
+    fn ranks_by_relevance_then_score() {
+        let posts = DashMap::new();
+        let cached = vec![
+            post("cat tax", 10.0),
+            post("my dog", 1000.0),
+            post("cat and another cat", 1.0),
+            post("cat", 100.0),
+        ];
+        let index = Index::default();
+        index.update("test", &cached);
+        posts.insert("test".to_string(), cached);
+
+        let subs = ["test".to_string()];
+        assert_eq!(
+            titles(&index.search(&posts, &subs, "CAT")),
+            ["cat and another cat", "cat", "cat tax"]
+        );
+        assert!(index.search(&posts, &subs, "bird").is_empty());
+        assert!(index.search(&posts, &[], "cat").is_empty());
+    }
+
+    #[test]
+    fn skips_posts_changed_since_indexing() {
+        let posts = DashMap::new();
+        let index = Index::default();
+        index.update("test", &[post("cat", 1.0)]);
+        posts.insert("test".to_string(), vec![post("cat", 1.0), post("dog", 1.0)]);
+
+        assert!(index
+            .search(&posts, &["test".to_string()], "cat")
+            .is_empty());
+    }
+
+    #[test]
+    fn skips_posts_replaced_since_indexing() {
+        let posts = DashMap::new();
+        let index = Index::default();
+        index.update("test", &[post("cat", 1.0), post("dog", 1.0)]);
+        posts.insert(
+            "test".to_string(),
+            vec![post("bird", 1.0), post("catfish", 1.0)],
+        );
+
+        assert!(index
+            .search(&posts, &["test".to_string()], "cat dog")
+            .is_empty());
+    }
+}
@@ -33,12 +33,46 @@ pub mod channel_id {
         where
             E: de::Error,
         {
-            match v.parse::<u64>() {
-                Ok(id) => Ok(ChannelId::from(id)),
-                Err(_) => Err(E::custom(format!(
-                    "channel ID cannot be parsed as a u64: {v}"
-                ))),
-            }
+            v.parse::<u64>()
+                .map(ChannelId::from)
+                .map_err(|_| E::custom(format!("channel ID cannot be parsed as a u64: {v}")))
+        }
+    }
+}
+
+/// Serde support for serenity's `GuildId` type.
+pub mod guild_id {
+    use poise::serenity_prelude::GuildId;
+    use serde::de::{self, Visitor};
+    use serde::{Deserializer, Serializer};
+
+    /// Serialize a `GuildId`'s inner value (u64) into a string.
+    #[allow(clippy::trivially_copy_pass_by_ref)] // Ref required by serde
+    pub fn serialize<S: Serializer>(guild_id: &GuildId, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&guild_id.0.to_string())
+    }
+
+    /// Deserialize a string into a `GuildId`.
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<GuildId, D::Error> {
+        deserializer.deserialize_str(GuildIdVisitor)
+    }
+
+    struct GuildIdVisitor;
+
+    impl<'de> Visitor<'de> for GuildIdVisitor {
+        type Value = GuildId;
+
+        fn expecting(&self, formatter: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            formatter.write_str("a string")
+        }
+
+        fn visit_str<E>(self, v: &str) -> Result<Self::Value, E>
+        where
+            E: de::Error,
+        {
+            v.parse::<u64>()
+                .map(GuildId::from)
+                .map_err(|_| E::custom(format!("guild ID cannot be parsed as a u64: {v}")))
         }
     }
 }
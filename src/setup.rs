@@ -1,21 +1,36 @@
 //! Bot setup helpers.
 
 use std::collections::HashMap;
+use std::str::FromStr;
 use std::sync::Arc;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 use std::{env, fs};
 
-use anyhow::{bail, Context as _, Error, Result};
+use anyhow::{bail, Context as _, Result};
+use chrono::{DateTime, Utc};
 use dashmap::DashMap;
-use poise::builtins::create_application_commands;
-use poise::futures_util::future;
+use mongodb::Database;
+use poise::futures_util::{future, stream, StreamExt};
+use poise::serenity::http::request::{Request, RequestBuilder};
+use poise::serenity::http::routing::RouteInfo;
 use poise::serenity_prelude::*;
-use poise::Framework;
 use roux::Subreddit;
+use serde_json::{json, Value};
 use tracing::{error, info, warn};
 
-use crate::data::{self, QuickPost};
-use crate::Data;
+use crate::data::{self, Blacklisted, Kind, QuickPost};
+use crate::db::{self, Autopost, ChannelInfo, Digest, Settings};
+use crate::filter::Filter;
+use crate::metrics;
+use crate::news::Summaries;
+use crate::search::Index;
+
+/// Default number of servers to register application commands on concurrently.
+const REGISTER_CONCURRENCY: usize = 8;
+/// Delay before database setup is first retried. The delay doubles after each failure.
+const DATABASE_RETRY_MIN: Duration = Duration::from_secs(1);
+/// Maximum delay between database setup retries.
+const DATABASE_RETRY_MAX: Duration = Duration::from_secs(5 * 60);
 
 /// Get and validate the bot token.
 #[tracing::instrument]
@@ -40,6 +55,26 @@ pub fn app_id() -> Result<u64> {
     }
 }
 
+/// Get and parse an optional environment variable.
+pub fn env_parse<T>(key: &str) -> Result<Option<T>>
+where
+    T: FromStr,
+    T::Err: std::error::Error + Send + Sync + 'static,
+{
+    env::var(key)
+        .ok()
+        .map(|val| {
+            val.parse()
+                .with_context(|| format!("invalid {key} environment variable"))
+        })
+        .transpose()
+}
+
+/// Get an optional environment variable as a duration (e.g. "30s", "5m").
+pub fn env_duration(key: &str) -> Result<Option<Duration>> {
+    Ok(env_parse::<humantime::Duration>(key)?.map(Into::into))
+}
+
 /// Generate an invite URL for the bot.
 #[tracing::instrument(skip_all)]
 pub async fn invite_url<H>(http: H, ready: &Ready)
@@ -60,47 +95,6 @@ where
     }
 }
 
-/// Set the bot's activity.
-#[tracing::instrument(skip_all)]
-pub async fn set_activity(ctx: &Context) {
-    let activity = match (
-        env::var("MEMER_ACTIVITY_TYPE"),
-        env::var("MEMER_ACTIVITY_NAME"),
-    ) {
-        (Ok(type_), Ok(name)) => {
-            let type_str = type_.as_str();
-            let activity_type = match type_str {
-                "competing" | "listening" | "playing" | "streaming" | "watching" => type_str,
-                _ => {
-                    tracing::warn!("invalid MEMER_ACTIVITY_TYPE environment variable");
-                    ""
-                }
-            };
-
-            match activity_type {
-                "competing" => Some(Activity::competing(name)),
-                "listening" => Some(Activity::listening(name)),
-                "playing" => Some(Activity::playing(name)),
-                "streaming" => {
-                    if let Ok(streaming) = env::var("MEMER_ACTIVITY_STREAMING") {
-                        Some(Activity::streaming(name, streaming))
-                    } else {
-                        tracing::warn!("missing MEMER_ACTIVITY_STREAMING environment variable");
-                        None
-                    }
-                }
-                "watching" => Some(Activity::watching(name)),
-                _ => None,
-            }
-        }
-        _ => None,
-    };
-
-    if let Some(activity) = activity {
-        ctx.set_activity(activity).await;
-    }
-}
-
 /// Load subreddits groups and subreddit names from `subs.json`.
 pub fn subs_from_file() -> Result<HashMap<String, Vec<String>>> {
     let path = env::current_dir()
@@ -114,61 +108,481 @@ pub fn subs_from_file() -> Result<HashMap<String, Vec<String>>> {
     Ok(subs)
 }
 
-/// Register application commands on all servers.
+/// Where application commands are registered.
+#[derive(Debug, Clone, Copy)]
+pub enum RegisterMode {
+    /// Register commands once for all servers. Global commands can take a while to show up.
+    Global,
+    /// Register commands on each server, on up to this many servers at a time.
+    Guilds(usize),
+}
+
+/// Get where application commands are registered.
+pub fn register_mode() -> Result<RegisterMode> {
+    if env_parse("MEMER_REGISTER_GLOBAL")?.unwrap_or(false) {
+        return Ok(RegisterMode::Global);
+    }
+
+    match env_parse("MEMER_REGISTER_CONCURRENCY")?.unwrap_or(REGISTER_CONCURRENCY) {
+        0 => bail!("invalid MEMER_REGISTER_CONCURRENCY environment variable"),
+        concurrency => Ok(RegisterMode::Guilds(concurrency)),
+    }
+}
+
+/// Register application commands globally or on all servers, skipping any that are already up to
+/// date.
 #[tracing::instrument(skip_all)]
 pub async fn register_commands(
     ctx: &Context,
-    framework: &Framework<Data, Error>,
+    mode: RegisterMode,
+    application_commands: CreateApplicationCommands,
     guilds: &[UnavailableGuild],
 ) {
-    info!("registering application commands on all servers...");
     let timer = Instant::now();
 
-    // FIXME: maybe some way turn this loop into tasks
-    for guild_id in guilds.iter().map(|guild| guild.id) {
-        let res = guild_id
-            .set_application_commands(ctx, |commands| {
-                *commands = create_application_commands(&framework.options().commands);
-                commands
-            })
-            .await;
+    match mode {
+        RegisterMode::Global => {
+            info!("registering application commands globally...");
 
-        if res.is_err() {
-            error!("failed to set application commands for guild: {}", guild_id);
+            match register_global(ctx, application_commands).await {
+                Ok(true) => info!("updated global application commands"),
+                Ok(false) => info!("global application commands are up to date"),
+                Err(e) => error!("failed to set global application commands: {e}"),
+            }
+
+            // Commands registered on servers before would show up twice
+            let guild_ids = guilds.iter().map(|guild| guild.id).collect::<Vec<_>>();
+            let cleared = stream::iter(guild_ids)
+                .map(|guild_id| clear_guild(ctx, guild_id))
+                .buffer_unordered(REGISTER_CONCURRENCY)
+                .filter(|cleared| future::ready(*cleared))
+                .count()
+                .await;
+            if cleared > 0 {
+                info!("cleared application commands from {cleared} servers");
+            }
+        }
+        RegisterMode::Guilds(concurrency) => {
+            info!("registering application commands on all servers...");
+
+            // Collected first, a stream over a borrowing iterator makes this future unspawnable
+            let guild_ids = guilds.iter().map(|guild| guild.id).collect::<Vec<_>>();
+            let results = stream::iter(guild_ids)
+                .map(|guild_id| {
+                    let application_commands = &application_commands;
+
+                    async move {
+                        let res = register_guild(ctx, guild_id, application_commands).await;
+                        (guild_id, res)
+                    }
+                })
+                .buffer_unordered(concurrency)
+                .collect::<Vec<_>>()
+                .await;
+
+            let (mut updated, mut current, mut failed) = (0, 0, Vec::new());
+            for (guild_id, res) in results {
+                match res {
+                    Ok(true) => updated += 1,
+                    Ok(false) => current += 1,
+                    Err(e) => failed.push(format!("{guild_id} ({e})")),
+                }
+            }
+
+            info!(
+                "{updated} updated, {current} up to date, {} failed",
+                failed.len()
+            );
+            if !failed.is_empty() {
+                error!(
+                    "failed to set application commands for guilds: {}",
+                    failed.join(", ")
+                );
+            }
         }
     }
 
     info!("done in {}", humantime::format_duration(timer.elapsed()));
 }
 
+/// Set global application commands if they differ from the existing ones. Returns whether the
+/// commands were updated.
+async fn register_global(
+    ctx: &Context,
+    application_commands: CreateApplicationCommands,
+) -> Result<bool, SerenityError> {
+    let existing = existing_commands(ctx, None).await?;
+
+    if commands_match(&existing, &application_commands) {
+        return Ok(false);
+    }
+
+    ApplicationCommand::set_global_application_commands(ctx, |commands| {
+        *commands = application_commands;
+        commands
+    })
+    .await?;
+
+    Ok(true)
+}
+
+/// Set a server's application commands if they differ from the existing ones. Returns whether the
+/// commands were updated.
+#[tracing::instrument(skip(ctx, application_commands), fields(guild = %guild_id))]
+async fn register_guild(
+    ctx: &Context,
+    guild_id: GuildId,
+    application_commands: &CreateApplicationCommands,
+) -> Result<bool, SerenityError> {
+    let existing = existing_commands(ctx, Some(guild_id)).await?;
+
+    if commands_match(&existing, application_commands) {
+        return Ok(false);
+    }
+
+    guild_id
+        .set_application_commands(ctx, |commands| {
+            *commands = application_commands.clone();
+            commands
+        })
+        .await?;
+
+    Ok(true)
+}
+
+/// Remove a server's application commands, if it has any. Returns whether any were removed.
+#[tracing::instrument(skip(ctx), fields(guild = %guild_id))]
+async fn clear_guild(ctx: &Context, guild_id: GuildId) -> bool {
+    let res = match existing_commands(ctx, Some(guild_id)).await {
+        Ok(existing) if existing.is_empty() => return false,
+        Ok(_) => guild_id
+            .set_application_commands(ctx, |commands| commands)
+            .await
+            .map(|_| true),
+        Err(e) => Err(e),
+    };
+
+    res.unwrap_or_else(|e| {
+        warn!("failed to clear application commands: {e}");
+        false
+    })
+}
+
+/// Get the application commands registered globally, or on a server. They're kept as JSON since
+/// serenity's model leaves out fields like `autocomplete`.
+async fn existing_commands(
+    ctx: &Context,
+    guild_id: Option<GuildId>,
+) -> Result<Vec<Value>, SerenityError> {
+    let application_id = ctx
+        .http
+        .application_id()
+        .ok_or(HttpError::ApplicationIdMissing)?;
+    let route = guild_id.map_or(
+        RouteInfo::GetGlobalApplicationCommands { application_id },
+        |guild_id| RouteInfo::GetGuildApplicationCommands {
+            application_id,
+            guild_id: guild_id.0,
+        },
+    );
+
+    ctx.http
+        .fire(Request::new(RequestBuilder::new(route)))
+        .await
+}
+
+/// Whether existing application commands are the same as the commands to be registered.
+fn commands_match(existing: &[Value], commands: &CreateApplicationCommands) -> bool {
+    let mut existing = existing.iter().map(command_signature).collect::<Vec<_>>();
+    let mut commands = commands.0.iter().map(command_signature).collect::<Vec<_>>();
+
+    existing.sort_by(|a, b| a["name"].as_str().cmp(&b["name"].as_str()));
+    commands.sort_by(|a, b| a["name"].as_str().cmp(&b["name"].as_str()));
+
+    existing == commands
+}
+
+/// Every field of an application command or command option that can be registered, compared when
+/// deciding whether commands need to be updated, with discord's defaults filled in. Fields that only
+/// exist on registered commands, like IDs and versions, are left out.
+fn command_signature(command: &Value) -> Value {
+    let defaults = json!({
+        "type": 1,
+        "name": "",
+        "description": "",
+        "name_localizations": {},
+        "description_localizations": {},
+        "default_member_permissions": null,
+        "dm_permission": true,
+        "required": false,
+        "choices": [],
+        "options": [],
+        "channel_types": [],
+        "min_value": null,
+        "max_value": null,
+        "min_length": null,
+        "max_length": null,
+        "autocomplete": false,
+    });
+    // Unwrap: the defaults are an object. Defaults are normalized too, or a command that leaves out
+    // e.g. its type would never match the registered command
+    let mut signature = normalize_numbers(&defaults).as_object().unwrap().clone();
+
+    for (key, value) in &mut signature {
+        match command.get(key) {
+            Some(Value::Null) | None => (),
+            Some(Value::Array(options)) if key == "options" => {
+                *value = options.iter().map(command_signature).collect();
+            }
+            Some(field) => *value = normalize_numbers(field),
+        }
+    }
+
+    Value::Object(signature)
+}
+
+/// Convert every number in a JSON value to a float, since discord may return e.g. `1` for `1.0`.
+fn normalize_numbers(value: &Value) -> Value {
+    match value {
+        Value::Number(n) => n.as_f64().map_or_else(|| value.clone(), |n| json!(n)),
+        Value::Array(values) => values.iter().map(normalize_numbers).collect(),
+        Value::Object(fields) => fields
+            .iter()
+            .map(|(key, value)| (key.clone(), normalize_numbers(value)))
+            .collect(),
+        _ => value.clone(),
+    }
+}
+
+/// Check that the database is reachable, create indexes, and load channels, blacklisted posts, last
+/// posts, bans, scheduled posts, digests, media limits, post filters and settings into the cache.
+/// Failures are retried with exponential backoff until the database is set up.
+#[allow(clippy::too_many_arguments)] // One cache per collection
+#[tracing::instrument(skip_all)]
+pub async fn database(
+    db: &Database,
+    channels: &DashMap<ChannelId, ChannelInfo>,
+    blacklist: &DashMap<ChannelId, Vec<Blacklisted>>,
+    last_post: &DashMap<ChannelId, QuickPost>,
+    bans: &DashMap<ChannelId, Vec<String>>,
+    autoposts: &DashMap<(ChannelId, String), Autopost>,
+    digests: &DashMap<(ChannelId, String), Digest>,
+    media: &DashMap<ChannelId, Kind>,
+    filters: &DashMap<GuildId, Filter>,
+    settings: &RwLock<Settings>,
+) {
+    info!("connecting to the database...");
+    let timer = Instant::now();
+    let mut delay = DATABASE_RETRY_MIN;
+
+    // Loaders keep what's already cached, so a retry doesn't clobber changes made in between
+    while let Err(e) = load_database(
+        db, channels, blacklist, last_post, bans, autoposts, digests, media, filters, settings,
+    )
+    .await
+    {
+        error!(
+            "database setup failed, retrying in {}: {e:#}",
+            humantime::format_duration(delay)
+        );
+        tokio::time::sleep(delay).await;
+        delay = (delay * 2).min(DATABASE_RETRY_MAX);
+    }
+
+    info!("done in {}", humantime::format_duration(timer.elapsed()));
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn load_database(
+    db: &Database,
+    channels: &DashMap<ChannelId, ChannelInfo>,
+    blacklist: &DashMap<ChannelId, Vec<Blacklisted>>,
+    last_post: &DashMap<ChannelId, QuickPost>,
+    bans: &DashMap<ChannelId, Vec<String>>,
+    autoposts: &DashMap<(ChannelId, String), Autopost>,
+    digests: &DashMap<(ChannelId, String), Digest>,
+    media: &DashMap<ChannelId, Kind>,
+    filters: &DashMap<GuildId, Filter>,
+    settings: &RwLock<Settings>,
+) -> Result<()> {
+    db::ping(db).await?;
+    db::ensure_indexes(db).await?;
+    db::all_channels(db, channels).await?;
+    db::blacklist(db, blacklist).await?;
+    db::last_posts(db, last_post).await?;
+    db::bans(db, bans).await?;
+    db::autoposts(db, autoposts).await?;
+    db::digests(db, digests).await?;
+    db::media(db, media).await?;
+    db::filters(db, filters).await?;
+    if let Some(saved) = db::settings(db).await? {
+        *settings.write().await = saved;
+    }
+
+    Ok(())
+}
+
 /// Get the first 100 hot posts for all subreddits in `data::SUBS`.
 #[tracing::instrument(skip_all)]
-pub async fn all_hot_posts() -> Arc<DashMap<String, Vec<QuickPost>>> {
+pub async fn all_hot_posts(
+    posts: Arc<DashMap<String, Vec<QuickPost>>>,
+    refreshed: Arc<DashMap<String, DateTime<Utc>>>,
+    index: Arc<Index>,
+    summaries: Arc<Summaries>,
+) -> usize {
     info!("populating subreddit post data...");
     let timer = Instant::now();
 
-    // Unwrap: data::SUBS is set before this is called
-    let subs = data::SUBS.get().unwrap().values().flatten();
-    let (lo, hi) = subs.size_hint();
-    let posts = Arc::new(DashMap::with_capacity(hi.unwrap_or(lo)));
+    let failed = future::join_all(data::all_subs().into_iter().map(|sub| {
+        let posts = posts.clone();
+        let refreshed = refreshed.clone();
+        let index = index.clone();
+        let summaries = summaries.clone();
 
-    future::join_all(subs.map(|sub| tokio::spawn(hot_posts(sub, posts.clone())))).await;
+        tokio::spawn(async move { hot_posts(&sub, posts, refreshed, index, summaries).await })
+    }))
+    .await
+    .into_iter()
+    .filter(|res| match res {
+        Ok(Ok(_)) => false,
+        Ok(Err(e)) => {
+            error!("{e:#}");
+            true
+        }
+        Err(e) => {
+            error!("{e}");
+            true
+        }
+    })
+    .count();
 
-    info!("done in {}", humantime::format_duration(timer.elapsed()));
-    posts
+    info!(
+        "done in {}, {failed} failed",
+        humantime::format_duration(timer.elapsed())
+    );
+    failed
 }
 
-/// Retrieve the first 100 hot posts for the specified subreddit and store them as `QuickPost`s.
+/// Retrieve the first 100 hot posts for the specified subreddit, store them as `QuickPost`s and
+/// index them. Returns the number of posts stored.
 #[tracing::instrument(skip_all, fields(subreddit = %sub))]
-async fn hot_posts(sub: &str, posts: Arc<DashMap<String, Vec<QuickPost>>>) {
+pub async fn hot_posts(
+    sub: &str,
+    posts: Arc<DashMap<String, Vec<QuickPost>>>,
+    refreshed: Arc<DashMap<String, DateTime<Utc>>>,
+    index: Arc<Index>,
+    summaries: Arc<Summaries>,
+) -> Result<usize> {
     let subreddit = Subreddit::new(sub);
-    let hot = match subreddit.hot(100, None).await {
-        Ok(hot) => hot,
-        Err(_) => {
-            error!("failed to get hot posts for {sub}");
-            return;
-        }
-    };
+    let hot = subreddit.hot(100, None).await;
+    metrics::reddit_fetch(sub, hot.is_ok());
+    let hot = hot.with_context(|| format!("failed to get hot posts for r/{sub}"))?;
+
+    let hot = data::submissions_to_quickposts(hot);
+    let count = hot.len();
+    summaries.prefetch(sub, &hot);
+    // Cache the posts before indexing them, keeping them locked until the index is updated
+    index.update(sub, &posts.entry(sub.to_string()).insert(hot));
+    refreshed.insert(sub.to_string(), Utc::now());
+
+    Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn commands() -> CreateApplicationCommands {
+        let mut commands = CreateApplicationCommands::default();
+        commands
+            .create_application_command(|command| {
+                command
+                    .name("meme")
+                    .description("Get a meme")
+                    .create_option(|option| {
+                        option
+                            .name("sub")
+                            .description("Subreddit to get a meme from")
+                            .kind(ApplicationCommandOptionType::String)
+                            .required(false)
+                    })
+            })
+            .create_application_command(|command| command.name("ping").description("Pong"));
+        commands
+    }
+
+    /// The commands as discord returns them once registered: in a different order, with different
+    /// key order, defaults filled in and registration fields added.
+    fn registered() -> Vec<Value> {
+        vec![
+            json!({
+                "version": "2",
+                "type": 1,
+                "name": "ping",
+                "id": "2",
+                "description": "Pong",
+                "dm_permission": true,
+                "default_member_permissions": null,
+                "application_id": "1",
+            }),
+            json!({
+                "options": [{
+                    "required": false,
+                    "description": "Subreddit to get a meme from",
+                    "name": "sub",
+                    "type": 3,
+                }],
+                "id": "1",
+                "application_id": "1",
+                "description": "Get a meme",
+                "name": "meme",
+                "type": 1,
+                "version": "1",
+            }),
+        ]
+    }
+
+    #[test]
+    fn registered_commands_match() {
+        assert!(commands_match(&registered(), &commands()));
+    }
+
+    #[test]
+    fn changed_commands_dont_match() {
+        let mut changed = registered();
+        changed[0]["description"] = json!("Ping");
+        assert!(!commands_match(&changed, &commands()));
 
-    posts.insert(sub.to_string(), data::submissions_to_quickposts(hot));
+        let mut changed = registered();
+        changed[1]["options"][0]["required"] = json!(true);
+        assert!(!commands_match(&changed, &commands()));
+
+        let mut changed = registered();
+        changed[1]["options"][0]["type"] = json!(4);
+        assert!(!commands_match(&changed, &commands()));
+
+        let mut changed = registered();
+        changed.pop();
+        assert!(!commands_match(&changed, &commands()));
+    }
+
+    #[test]
+    fn option_order_matters() {
+        let mut commands = commands();
+        commands.0[0]["options"]
+            .as_array_mut()
+            .unwrap()
+            .push(json!({"type": 5, "name": "nsfw", "description": "Allow NSFW"}));
+        let mut registered = registered();
+        registered[1]["options"] = json!([
+            {"type": 5, "name": "nsfw", "description": "Allow NSFW"},
+            {"type": 3, "name": "sub", "description": "Subreddit to get a meme from"},
+        ]);
+
+        // Options are shown in the order they're registered, so a reordered option is a change
+        assert!(!commands_match(&registered, &commands));
+
+        registered[1]["options"].as_array_mut().unwrap().reverse();
+        assert!(commands_match(&registered, &commands));
+    }
 }
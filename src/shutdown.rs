@@ -0,0 +1,114 @@
+//! Graceful shutdown.
+
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Result;
+use once_cell::sync::OnceCell;
+use tokio::sync::Notify;
+use tokio::time::Instant;
+
+use crate::setup;
+
+/// Default time allowed for shutting down before pending work is abandoned.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Shutdown state, and the number of commands that are still being handled.
+#[derive(Debug, Default)]
+pub struct Shutdown {
+    stopping: AtomicBool,
+    deadline: OnceCell<Instant>,
+    in_flight: AtomicUsize,
+    idle: Notify,
+}
+
+impl Shutdown {
+    /// Stop accepting commands, and set the deadline for shutting down.
+    pub fn stop(&self, timeout: Duration) {
+        let _ = self.deadline.set(Instant::now() + timeout);
+        self.stopping.store(true, Ordering::Release);
+    }
+
+    /// Whether the bot is shutting down.
+    pub fn is_stopping(&self) -> bool {
+        self.stopping.load(Ordering::Acquire)
+    }
+
+    /// The deadline for shutting down, if shutdown has started.
+    pub fn deadline(&self) -> Option<Instant> {
+        self.deadline.get().copied()
+    }
+
+    /// Track a command that started, until the returned guard is dropped.
+    pub fn command_started(self: &Arc<Self>) -> CommandGuard {
+        self.in_flight.fetch_add(1, Ordering::AcqRel);
+
+        CommandGuard {
+            shutdown: self.clone(),
+            started: std::time::Instant::now(),
+        }
+    }
+
+    /// Track a command that finished, successfully or not.
+    fn command_finished(&self) {
+        if self.in_flight.fetch_sub(1, Ordering::AcqRel) == 1 {
+            self.idle.notify_waiters();
+        }
+    }
+
+    /// Wait until no commands are being handled.
+    pub async fn drained(&self) {
+        loop {
+            // Register interest before checking, so a notification in between isn't missed
+            let idle = self.idle.notified();
+
+            if self.in_flight.load(Ordering::Acquire) == 0 {
+                return;
+            }
+
+            idle.await;
+        }
+    }
+}
+
+/// A command that's being handled. The command is tracked as finished when the guard is dropped,
+/// however it finishes.
+#[derive(Debug)]
+pub struct CommandGuard {
+    shutdown: Arc<Shutdown>,
+    /// When the command started.
+    pub started: std::time::Instant,
+}
+
+impl Drop for CommandGuard {
+    fn drop(&mut self) {
+        self.shutdown.command_finished();
+    }
+}
+
+/// Get the time allowed for shutting down.
+pub fn timeout() -> Result<Duration> {
+    Ok(setup::env_duration("MEMER_SHUTDOWN_TIMEOUT")?.unwrap_or(TIMEOUT))
+}
+
+/// Wait for a SIGINT (ctrl-c) or SIGTERM signal. Returns the name of the signal.
+pub async fn signal() -> Result<&'static str> {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut sigterm = signal(SignalKind::terminate())?;
+
+        tokio::select! {
+            res = tokio::signal::ctrl_c() => res.map(|_| "SIGINT").map_err(Into::into),
+            _ = sigterm.recv() => Ok("SIGTERM"),
+        }
+    }
+
+    #[cfg(not(unix))]
+    {
+        tokio::signal::ctrl_c().await?;
+        Ok("ctrl-c")
+    }
+}
@@ -0,0 +1,301 @@
+//! On-disk snapshots of the post cache.
+
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use std::{env, fs};
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, TimeZone, Utc};
+use dashmap::DashMap;
+use tracing::{info, warn};
+
+use crate::data::{self, QuickPost};
+use crate::news::Summaries;
+use crate::search::Index;
+use crate::setup;
+
+/// Default interval between snapshots.
+const INTERVAL: Duration = Duration::from_secs(10 * 60);
+/// Default age after which a subreddit's cached posts are stale.
+const STALE_AFTER: Duration = Duration::from_secs(60 * 60);
+/// Bytes at the start of every snapshot.
+const MAGIC: &[u8; 8] = b"memersnp";
+/// Version of the snapshot format. bincode doesn't support `#[serde(default)]` or skipping fields,
+/// so this must be bumped whenever `SubSnapshot` or `QuickPost` changes.
+const VERSION: u32 = 1;
+
+/// A subreddit's cached posts and the time they were fetched.
+#[derive(serde::Serialize, serde::Deserialize)]
+struct SubSnapshot {
+    sub: String,
+    refreshed: i64,
+    posts: Vec<QuickPost>,
+}
+
+/// Get the snapshot file path.
+pub fn path() -> Result<PathBuf> {
+    match env::var("MEMER_SNAPSHOT_PATH") {
+        Ok(path) => Ok(PathBuf::from(path)),
+        Err(_) => Ok(env::current_dir()
+            .context("failed to get cwd")?
+            .join("posts.snapshot")),
+    }
+}
+
+/// Get the interval between snapshots.
+pub fn interval() -> Result<Duration> {
+    Ok(setup::env_duration("MEMER_SNAPSHOT_INTERVAL")?.unwrap_or(INTERVAL))
+}
+
+/// Get the age after which a subreddit's cached posts are stale.
+pub fn stale_after() -> Result<Duration> {
+    Ok(setup::env_duration("MEMER_SNAPSHOT_STALE")?.unwrap_or(STALE_AFTER))
+}
+
+/// Load a snapshot into the post cache and search index. A missing snapshot, or one written by a
+/// different version of the bot, is not an error.
+#[tracing::instrument(skip_all)]
+pub fn load(
+    path: &Path,
+    stale_after: Duration,
+    posts: &DashMap<String, Vec<QuickPost>>,
+    refreshed: &DashMap<String, DateTime<Utc>>,
+    index: &Index,
+    summaries: &Arc<Summaries>,
+) -> Result<()> {
+    let buf = match fs::read(path) {
+        Ok(buf) => buf,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            info!("no snapshot found at {}", path.display());
+            return Ok(());
+        }
+        Err(e) => {
+            return Err(e).with_context(|| format!("failed to read file: {}", path.display()))
+        }
+    };
+    let Some(buf) = buf
+        .strip_prefix(MAGIC)
+        .and_then(|buf| buf.strip_prefix(VERSION.to_le_bytes().as_slice()))
+    else {
+        info!("discarding outdated snapshot at {}", path.display());
+        return Ok(());
+    };
+    let subs = bincode::deserialize::<Vec<SubSnapshot>>(buf)
+        .with_context(|| format!("failed to deserialize file: {}", path.display()))?;
+
+    for SubSnapshot {
+        sub,
+        refreshed: time,
+        posts: mut sub_posts,
+    } in subs
+    {
+        sub_posts.iter_mut().for_each(QuickPost::ensure_key);
+        // Subreddits without a valid refresh time are stale
+        if let Some(time) = Utc.timestamp_opt(time, 0).earliest() {
+            refreshed.insert(sub.clone(), time);
+        }
+        summaries.prefetch(&sub, &sub_posts);
+        // Cache the posts before indexing them, keeping them locked until the index is updated
+        index.update(&sub, &posts.entry(sub.clone()).insert(sub_posts));
+    }
+
+    let stale = posts
+        .iter()
+        .filter(|entry| data::is_stale(refreshed, entry.key(), stale_after))
+        .count();
+    info!(
+        "loaded {} subreddits from snapshot, {stale} are stale",
+        posts.len()
+    );
+    Ok(())
+}
+
+/// Write the post cache to a snapshot. The snapshot is written to a temporary file first so a
+/// failed write doesn't clobber the previous snapshot.
+#[tracing::instrument(skip_all)]
+pub fn save(
+    path: &Path,
+    posts: &DashMap<String, Vec<QuickPost>>,
+    refreshed: &DashMap<String, DateTime<Utc>>,
+) -> Result<()> {
+    let subs = posts
+        .iter()
+        .map(|entry| SubSnapshot {
+            sub: entry.key().clone(),
+            refreshed: refreshed
+                .get(entry.key())
+                .map_or(0, |time| time.timestamp()),
+            posts: entry.value().clone(),
+        })
+        .collect::<Vec<_>>();
+    let mut buf = MAGIC.to_vec();
+    buf.extend_from_slice(&VERSION.to_le_bytes());
+    bincode::serialize_into(&mut buf, &subs).context("failed to serialize snapshot")?;
+    let tmp = path.with_extension("tmp");
+
+    fs::write(&tmp, buf).with_context(|| format!("failed to write file: {}", tmp.display()))?;
+    fs::rename(&tmp, path).with_context(|| format!("failed to write file: {}", path.display()))?;
+
+    info!("saved {} subreddits to snapshot", subs.len());
+    Ok(())
+}
+
+/// Periodically write the post cache to a snapshot.
+pub async fn periodic(
+    path: PathBuf,
+    interval: Duration,
+    posts: Arc<DashMap<String, Vec<QuickPost>>>,
+    refreshed: Arc<DashMap<String, DateTime<Utc>>>,
+) {
+    let mut ticker = tokio::time::interval(interval);
+
+    // The first tick completes immediately, and there is nothing new to save yet
+    ticker.tick().await;
+
+    loop {
+        ticker.tick().await;
+
+        let path = path.clone();
+        let posts = posts.clone();
+        let refreshed = refreshed.clone();
+
+        let res = tokio::task::spawn_blocking(move || save(&path, &posts, &refreshed)).await;
+
+        if let Err(e) = res.unwrap_or_else(|e| Err(e.into())) {
+            warn!("failed to save snapshot: {e:#}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn post(title: &str) -> QuickPost {
+        QuickPost {
+            title: title.to_string(),
+            score: 1.0,
+            content: format!("https://i.redd.it/{title}.png"),
+            nsfw: false,
+            permalink: format!("/r/memes/comments/{title}/"),
+            sub: "memes".to_string(),
+            flair: None,
+            spoiler: false,
+            kind: data::Kind::Image,
+            domain: "i.redd.it".to_string(),
+            created: 0,
+            key: String::new(),
+        }
+    }
+
+    /// A snapshot path unique to the test, removed when dropped.
+    struct TempPath(PathBuf);
+
+    impl TempPath {
+        fn new(name: &str) -> Self {
+            Self(env::temp_dir().join(format!("memer-{name}-{}.snapshot", std::process::id())))
+        }
+    }
+
+    impl Drop for TempPath {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+
+    /// Caches loaded from a snapshot.
+    struct Loaded {
+        posts: DashMap<String, Vec<QuickPost>>,
+        refreshed: DashMap<String, DateTime<Utc>>,
+        index: Index,
+    }
+
+    /// Load a snapshot into empty caches.
+    fn load_into(path: &Path) -> Loaded {
+        let loaded = Loaded {
+            posts: DashMap::new(),
+            refreshed: DashMap::new(),
+            index: Index::default(),
+        };
+        let summaries = Arc::new(Summaries::new().unwrap());
+
+        load(
+            path,
+            STALE_AFTER,
+            &loaded.posts,
+            &loaded.refreshed,
+            &loaded.index,
+            &summaries,
+        )
+        .unwrap();
+        loaded
+    }
+
+    #[test]
+    fn round_trips() {
+        let path = TempPath::new("round-trip");
+        let posts = DashMap::new();
+        let refreshed = DashMap::new();
+        let time = Utc.timestamp_opt(1_700_000_000, 0).unwrap();
+        posts.insert("memes".to_string(), vec![post("first"), post("second")]);
+        posts.insert("dankmemes".to_string(), vec![post("third")]);
+        refreshed.insert("memes".to_string(), time);
+
+        save(&path.0, &posts, &refreshed).unwrap();
+        let loaded = load_into(&path.0);
+
+        let titles = |sub: &str| {
+            loaded
+                .posts
+                .get(sub)
+                .unwrap()
+                .iter()
+                .map(|post| post.title.clone())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(titles("memes"), ["first", "second"]);
+        assert_eq!(titles("dankmemes"), ["third"]);
+        assert!(loaded
+            .posts
+            .get("memes")
+            .unwrap()
+            .iter()
+            .all(|post| !post.key.is_empty()));
+        assert_eq!(loaded.refreshed.get("memes").as_deref(), Some(&time));
+        // Subreddits saved without a refresh time load as stale
+        assert!(data::is_stale(&loaded.refreshed, "dankmemes", STALE_AFTER));
+
+        let found = loaded
+            .index
+            .search(&loaded.posts, &["memes".to_string()], "second");
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].title, "second");
+    }
+
+    #[test]
+    fn discards_other_formats() {
+        let path = TempPath::new("formats");
+        let posts = DashMap::new();
+        posts.insert("memes".to_string(), vec![post("first")]);
+        save(&path.0, &posts, &DashMap::new()).unwrap();
+        let saved = fs::read(&path.0).unwrap();
+
+        let mut bumped = saved.clone();
+        bumped[MAGIC.len()..MAGIC.len() + 4].copy_from_slice(&(VERSION + 1).to_le_bytes());
+        fs::write(&path.0, bumped).unwrap();
+        assert!(load_into(&path.0).posts.is_empty());
+
+        let mut wrong_magic = saved;
+        wrong_magic[..MAGIC.len()].copy_from_slice(b"notasnap");
+        fs::write(&path.0, wrong_magic).unwrap();
+        assert!(load_into(&path.0).posts.is_empty());
+    }
+
+    #[test]
+    fn missing_snapshot_is_empty() {
+        let path = TempPath::new("missing");
+
+        assert!(load_into(&path.0).posts.is_empty());
+    }
+}
@@ -0,0 +1,178 @@
+//! Checking that cached posts haven't been removed, and their media still exists.
+
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Result};
+use dashmap::DashMap;
+use reqwest::StatusCode;
+use serde_json::Value;
+use tracing::debug;
+
+use crate::data::{Kind, QuickPost};
+
+/// Timeout of validation requests.
+const TIMEOUT: Duration = Duration::from_secs(3);
+/// How long a validation result is trusted before the post is checked again.
+const TTL: Duration = Duration::from_secs(30 * 60);
+/// Maximum number of validation results kept.
+const MAX_RESULTS: usize = 10_000;
+
+/// Validates posts before they're sent, caching the results.
+#[derive(Debug)]
+pub struct Validator {
+    client: reqwest::Client,
+    /// Map of media URLs or self post permalinks, and whether they passed and when the result
+    /// expires.
+    results: DashMap<String, (bool, Instant)>,
+}
+
+impl Validator {
+    pub fn new() -> Result<Self> {
+        Ok(Self {
+            client: reqwest::Client::builder()
+                .timeout(TIMEOUT)
+                .user_agent(concat!("memer/", env!("CARGO_PKG_VERSION")))
+                .build()?,
+            results: DashMap::new(),
+        })
+    }
+
+    /// Whether a post can still be sent. Media must still exist, and self posts' text mustn't have
+    /// been removed or deleted. Posts that can't be checked, e.g. because of a timeout, pass
+    /// without caching the result, so an outage doesn't empty the cache.
+    #[tracing::instrument(skip_all, fields(permalink = %post.permalink))]
+    pub async fn check(&self, post: &QuickPost) -> bool {
+        let key = match post.kind {
+            Kind::Image | Kind::Gif | Kind::Video => &post.content,
+            Kind::Text => &post.permalink,
+            Kind::Link => return true,
+        };
+        if let Some(result) = self.results.get(key) {
+            if result.1 > Instant::now() {
+                return result.0;
+            }
+        }
+
+        let result = match post.kind {
+            Kind::Text => self.text_exists(post).await,
+            _ => self.media_exists(&post.content).await,
+        };
+        match result {
+            Ok(valid) => {
+                if self.results.len() >= MAX_RESULTS {
+                    self.evict();
+                }
+                self.results
+                    .insert(key.clone(), (valid, Instant::now() + TTL));
+                if !valid {
+                    debug!("post failed validation");
+                }
+                valid
+            }
+            Err(e) => {
+                debug!("failed to validate post: {e:#}");
+                true
+            }
+        }
+    }
+
+    /// Forget expired results. If that frees less than a quarter of the space, the oldest results
+    /// are forgotten too, so this runs at most once every `MAX_RESULTS / 4` new results.
+    fn evict(&self) {
+        let now = Instant::now();
+        self.results.retain(|_, (_, expires)| *expires > now);
+
+        let excess = (self.results.len() + MAX_RESULTS / 4).saturating_sub(MAX_RESULTS);
+        if excess > 0 {
+            let mut expiries = self
+                .results
+                .iter()
+                .map(|result| result.1)
+                .collect::<Vec<_>>();
+            expiries.sort_unstable();
+            let cutoff = expiries[excess - 1];
+            self.results.retain(|_, (_, expires)| *expires > cutoff);
+        }
+    }
+
+    /// Whether media at a URL still exists. Some hosts redirect removed media to a placeholder
+    /// image instead of responding with an error.
+    async fn media_exists(&self, url: &str) -> Result<bool> {
+        let res = self.client.head(url).send().await?;
+
+        let missing = matches!(res.status(), StatusCode::NOT_FOUND | StatusCode::GONE);
+
+        Ok(!missing && !res.url().path().ends_with("/removed.png"))
+    }
+
+    /// Whether a self post's text hasn't been removed or deleted.
+    async fn text_exists(&self, post: &QuickPost) -> Result<bool> {
+        let id = post
+            .permalink
+            .split('/')
+            .skip_while(|segment| *segment != "comments")
+            .nth(1)
+            .ok_or_else(|| anyhow!("no post ID in permalink"))?;
+        let listing = self
+            .client
+            .get(format!("https://www.reddit.com/by_id/t3_{id}.json"))
+            .send()
+            .await?
+            .error_for_status()?
+            .json::<Value>()
+            .await?;
+
+        let Some(data) = listing.pointer("/data/children/0/data") else {
+            return Ok(false);
+        };
+        let selftext = data["selftext"].as_str().unwrap_or_default().trim();
+
+        Ok(selftext != "[removed]"
+            && selftext != "[deleted]"
+            && data["removed_by_category"].is_null())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn evicts_expired_then_oldest_results() {
+        let validator = Validator::new().unwrap();
+        let now = Instant::now();
+        for i in 0..MAX_RESULTS {
+            let expires = match i {
+                0..=99 => now,
+                // Older results have lower numbers
+                _ => now + TTL - Duration::from_millis((MAX_RESULTS - i) as u64),
+            };
+            validator
+                .results
+                .insert(i.to_string(), (i % 2 == 0, expires));
+        }
+
+        validator.evict();
+
+        assert_eq!(validator.results.len(), MAX_RESULTS * 3 / 4);
+        assert!(!validator.results.contains_key("0"));
+        assert!(!validator.results.contains_key("100"));
+        assert!(validator
+            .results
+            .contains_key(&(MAX_RESULTS - 1).to_string()));
+    }
+
+    #[test]
+    fn evicts_only_expired_results_when_enough_are_expired() {
+        let validator = Validator::new().unwrap();
+        let now = Instant::now();
+        for i in 0..MAX_RESULTS {
+            let expires = if i < MAX_RESULTS / 2 { now } else { now + TTL };
+            validator.results.insert(i.to_string(), (false, expires));
+        }
+
+        validator.evict();
+
+        assert_eq!(validator.results.len(), MAX_RESULTS / 2);
+    }
+}